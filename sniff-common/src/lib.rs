@@ -8,6 +8,11 @@ use network_types::{ip::Ipv4Hdr, tcp::TcpHdr, udp::UdpHdr};
 pub struct RawPacket {
     pub ip_hdr: Ipv4Hdr,
     pub proto_hdr: ProtoHdr,
+
+    /// number of valid bytes in `payload`
+    pub payload_len: u16,
+    /// leading bytes of the L4 payload, used by userspace to decode L7 protocols.
+    pub payload: [u8; RawPacket::PAYLOAD_LEN],
 }
 
 #[repr(C)]
@@ -19,12 +24,28 @@ pub enum ProtoHdr {
 impl RawPacket {
     pub const LEN: usize = mem::size_of::<Self>();
 
-    pub fn new(ip_hdr: Ipv4Hdr, proto_hdr: ProtoHdr) -> Self {
-        Self { ip_hdr, proto_hdr }
-    }
+    /// Maximum number of L4 payload bytes carried with each packet.
+    pub const PAYLOAD_LEN: usize = 512;
+}
+
+/// Settings of the in-kernel prefilter, evaluated before a packet is submitted to userspace
+pub struct Prefilter;
+
+impl Prefilter {
+    /// Maximum number of peer cidrs in the `FILTER_CIDRS` map.
+    pub const MAX_CIDRS: u32 = 1024;
+
+    /// Number of 64-bit words of the `FILTER_PORTS` destination port bitmap.
+    pub const PORT_WORDS: u32 = 65536 / 64;
+
+    /// `FILTER_FLAGS` bit enabling the peer cidr check
+    pub const CIDR: u32 = 1;
+
+    /// `FILTER_FLAGS` bit enabling the destination port check
+    pub const PORT: u32 = 1 << 1;
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Flow {
     All,
     Ingress,
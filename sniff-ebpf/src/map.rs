@@ -1,5 +1,17 @@
-use aya_ebpf::{macros::map, maps::RingBuf};
-use sniff_common::RawPacket;
+use aya_ebpf::{
+    macros::map,
+    maps::{lpm_trie::LpmTrie, Array, RingBuf},
+};
+use sniff_common::{Prefilter, RawPacket};
 
 #[map(name = "PACKET_DATA")]
 pub(crate) static PACKET_DATA: RingBuf = RingBuf::with_byte_size(4096 * RawPacket::LEN as u32, 0);
+
+/// Peer cidrs of the prefilter, keyed by the address in network byte order
+#[map(name = "FILTER_CIDRS")]
+pub(crate) static FILTER_CIDRS: LpmTrie<u32, u8> =
+    LpmTrie::with_max_entries(Prefilter::MAX_CIDRS, 0);
+
+/// Bitmap of the destination ports of the prefilter, bit `port % 64` of word `port / 64`
+#[map(name = "FILTER_PORTS")]
+pub(crate) static FILTER_PORTS: Array<u64> = Array::with_max_entries(Prefilter::PORT_WORDS, 0);
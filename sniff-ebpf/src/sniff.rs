@@ -5,7 +5,7 @@ use network_types::{
     tcp::TcpHdr,
     udp::UdpHdr,
 };
-use sniff_common::{ProtoHdr, RawPacket};
+use sniff_common::ProtoHdr;
 
 use crate::util;
 
@@ -15,16 +15,37 @@ pub(crate) fn try_sniff(ctx: &TcContext) -> Result<(), c_long> {
     match eth_hdr.ether_type {
         EtherType::Ipv4 => {
             let ipv4_hdr: Ipv4Hdr = ctx.load(EthHdr::LEN)?;
+            // the ip header may carry options, so the transport header offset follows 'ihl'
+            let l4_offset = EthHdr::LEN + (ipv4_hdr.ihl() as usize) * 4;
             match ipv4_hdr.proto {
                 IpProto::Tcp if util::is_tcp() => {
-                    let tcp_hdr: *const TcpHdr =
-                        util::ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN).map_err(|_| -1)?;
-                    util::submit(RawPacket::new(ipv4_hdr, ProtoHdr::Tcp(unsafe { *tcp_hdr })));
+                    let tcp_hdr: *const TcpHdr = util::ptr_at(ctx, l4_offset).map_err(|_| -1)?;
+                    let tcp_hdr = unsafe { *tcp_hdr };
+                    let (source, dst) = (u16::from_be(tcp_hdr.source), u16::from_be(tcp_hdr.dest));
+                    if !util::prefilter(&ipv4_hdr, dst) || !util::sample(&ipv4_hdr, source, dst) {
+                        return Ok(());
+                    }
+                    let payload_offset = l4_offset + (tcp_hdr.doff() as usize) * 4;
+                    util::submit(ctx, ipv4_hdr, ProtoHdr::Tcp(tcp_hdr), payload_offset);
                 }
                 IpProto::Udp if util::is_udp() => {
-                    let udp_hdr: *const UdpHdr =
-                        util::ptr_at(ctx, EthHdr::LEN + Ipv4Hdr::LEN).map_err(|_| -1)?;
-                    util::submit(RawPacket::new(ipv4_hdr, ProtoHdr::Udp(unsafe { *udp_hdr })));
+                    let udp_hdr: *const UdpHdr = util::ptr_at(ctx, l4_offset).map_err(|_| -1)?;
+                    let (source, dst) = unsafe {
+                        (
+                            u16::from_be((*udp_hdr).source),
+                            u16::from_be((*udp_hdr).dest),
+                        )
+                    };
+                    if !util::prefilter(&ipv4_hdr, dst) || !util::sample(&ipv4_hdr, source, dst) {
+                        return Ok(());
+                    }
+                    let payload_offset = l4_offset + UdpHdr::LEN;
+                    util::submit(
+                        ctx,
+                        ipv4_hdr,
+                        ProtoHdr::Udp(unsafe { *udp_hdr }),
+                        payload_offset,
+                    );
                 }
                 _ => {}
             }
@@ -1,9 +1,10 @@
 use core::mem;
 
-use aya_ebpf::programs::TcContext;
-use sniff_common::RawPacket;
+use aya_ebpf::{helpers::bpf_get_prandom_u32, maps::lpm_trie::Key, programs::TcContext};
+use network_types::ip::Ipv4Hdr;
+use sniff_common::{Prefilter, ProtoHdr, RawPacket};
 
-use crate::map::PACKET_DATA;
+use crate::map::{FILTER_CIDRS, FILTER_PORTS, PACKET_DATA};
 
 /// Used to indicate the traffic protocol of the detection, with the following conventions:
 /// * 0: ALL
@@ -12,6 +13,22 @@ use crate::map::PACKET_DATA;
 #[no_mangle]
 static SNIFF_PROTOCOL: i32 = 0;
 
+/// The traffic direction the program is attached to, same values as [sniff_common::Flow]
+#[no_mangle]
+static SNIFF_FLOW: i32 = 0;
+
+/// The checks of the prefilter that are enabled, see [Prefilter]
+#[no_mangle]
+static FILTER_FLAGS: u32 = 0;
+
+/// Submit one in `SAMPLE_RATE` packets, 0 and 1 disable sampling
+#[no_mangle]
+static SAMPLE_RATE: u32 = 0;
+
+/// When set, whole flows are sampled by hashing their addresses and ports instead of single packets
+#[no_mangle]
+static SAMPLE_FLOW_HASH: u32 = 0;
+
 #[inline]
 pub fn ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<*const T, ()> {
     let start = ctx.data();
@@ -25,14 +42,73 @@ pub fn ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<*const T, ()> {
     Ok((start + offset) as *const T)
 }
 
+/// Submit the packet headers and the leading payload bytes to userspace.
+///
+/// The packet is written in place in the reserved ring buffer entry, since
+/// [RawPacket] is too large to be built on the eBPF stack.
 #[inline]
-pub fn submit(pkt: RawPacket) {
-    if let Some(mut rb) = { PACKET_DATA.reserve(0) } {
-        unsafe { (*rb.as_mut_ptr()) = pkt };
+pub fn submit(ctx: &TcContext, ip_hdr: Ipv4Hdr, proto_hdr: ProtoHdr, payload_offset: usize) {
+    if let Some(mut rb) = { PACKET_DATA.reserve::<RawPacket>(0) } {
+        let pkt = rb.as_mut_ptr();
+        unsafe {
+            (*pkt).ip_hdr = ip_hdr;
+            (*pkt).proto_hdr = proto_hdr;
+            (*pkt).payload_len = match ctx.load_bytes(payload_offset, &mut (*pkt).payload) {
+                Ok(len) => len as u16,
+                Err(_) => 0,
+            };
+        }
         rb.submit(0);
     }
 }
 
+/// Whether the packet may match a rule, so that it is worth submitting to userspace.
+///
+/// The peer address is the source address of ingress traffic and the destination address of egress traffic.
+#[inline]
+pub fn prefilter(ip_hdr: &Ipv4Hdr, dst_port: u16) -> bool {
+    let flags = unsafe { core::ptr::read_volatile(&FILTER_FLAGS) };
+
+    if flags & Prefilter::CIDR != 0 {
+        let peer = if unsafe { core::ptr::read_volatile(&SNIFF_FLOW) } == 1 {
+            ip_hdr.src_addr
+        } else {
+            ip_hdr.dst_addr
+        };
+        if FILTER_CIDRS.get(&Key::new(32, peer)).is_none() {
+            return false;
+        }
+    }
+
+    if flags & Prefilter::PORT != 0 {
+        match FILTER_PORTS.get((dst_port / 64) as u32) {
+            Some(bits) if bits & (1 << (dst_port % 64)) != 0 => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Whether the packet is kept by the sampling of the interface
+#[inline]
+pub fn sample(ip_hdr: &Ipv4Hdr, source: u16, dst: u16) -> bool {
+    let rate = unsafe { core::ptr::read_volatile(&SAMPLE_RATE) };
+    if rate <= 1 {
+        return true;
+    }
+
+    let hash = if unsafe { core::ptr::read_volatile(&SAMPLE_FLOW_HASH) } != 0 {
+        // symmetric, so that both directions of a flow are sampled together
+        let hash = (ip_hdr.src_addr ^ ip_hdr.dst_addr) ^ ((source ^ dst) as u32);
+        hash.wrapping_mul(0x9e37_79b1) >> 8
+    } else {
+        unsafe { bpf_get_prandom_u32() }
+    };
+
+    hash % rate == 0
+}
+
 #[inline]
 pub fn is_tcp() -> bool {
     let sniff_protocol = unsafe { core::ptr::read_volatile(&SNIFF_PROTOCOL) };
@@ -1,25 +1,42 @@
 use std::{
+    collections::{HashMap, HashSet},
     net::{IpAddr, Ipv4Addr},
     sync::Arc,
+    time::Duration,
 };
 
 use colored::Colorize;
-use log::{info, trace};
+use log::{debug, error, info, trace};
 use sniff_common::Flow;
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, task::JoinHandle};
 
 use crate::{
+    capture::{Limits, Summary},
     cidr::PrefixTree,
     collector::{self, CollectorMap},
+    config::ConfigItem,
+    dns::{self, DnsTracker},
     ebpf,
-    filter::Filter,
+    filter::{Filter, MatchPolicy},
+    flow::FlowTable,
+    http::{self, HttpMessage},
+    iface::{self, IfaceSet, SampleRates},
     metrics,
+    netlink::{self, LinkEvent, LinkWatcher},
+    netns::{self, Netns},
     network::NetworkPacket,
-    util,
+    output::{self, Format},
+    prefilter::{Predicate, Prefilter},
+    reload::RuleSet,
+    tls, util,
 };
 
+/// A network interface of a network namespace, and a traffic direction
+type Attachment = (Option<Netns>, String, Flow);
+
 pub struct Application {
-    pub ifaces: Vec<String>,
+    /// the network interface selectors by network namespace, see [IfaceSet]
+    pub ifaces: Vec<(Option<Netns>, IfaceSet)>,
 
     pub trie: PrefixTree<Arc<Box<Filter>>>,
     pub empty_filter: Option<Vec<Arc<Box<Filter>>>>,
@@ -28,11 +45,33 @@ pub struct Application {
     pub tx: mpsc::Sender<NetworkPacket>,
 
     pub collector: Option<Arc<CollectorMap>>,
+    pub policy: MatchPolicy,
+    /// the sample rates by `netns` label and network interface selector, see [iface::sample_rate]
+    pub sample_rates: SampleRates,
+    pub sample_flow_hash: bool,
+    pub dns_tracker: Arc<DnsTracker>,
+    pub flows: Arc<FlowTable>,
+
+    flow: Flow,
+    prefilter: Prefilter,
+    /// the format of the packets written to stdout, otherwise they are logged at trace level
+    output: Option<Format>,
+    /// the limits of a CLI capture, and the totals of the captured packets
+    limits: Limits,
+    summary: Option<Summary>,
+    /// the attached eBPF programs by network interface and traffic direction,
+    /// aborting the task detaches its program
+    loaders: HashMap<Attachment, (Predicate, JoinHandle<()>)>,
+    reload_rx: Option<mpsc::Receiver<RuleSet>>,
+    rule_items: HashMap<String, ConfigItem>,
+    /// the attachable network interfaces by network namespace, those of the namespace of netsniff
+    /// are kept up to date by [LinkWatcher], those of other namespaces are listed periodically
+    links: HashMap<Option<Netns>, HashSet<String>>,
 }
 
 impl Application {
     pub fn new(
-        ifaces: Vec<String>,
+        ifaces: Vec<(Option<Netns>, IfaceSet)>,
         trie: PrefixTree<Arc<Box<Filter>>>,
         empty_filter: Option<Vec<Arc<Box<Filter>>>>,
         collector: Option<CollectorMap>,
@@ -47,91 +86,370 @@ impl Application {
             tx,
             empty_filter,
             collector,
+            policy: MatchPolicy::default(),
+            sample_rates: SampleRates::new(),
+            sample_flow_hash: false,
+            dns_tracker: Arc::new(DnsTracker::default()),
+            flows: Arc::new(FlowTable::default()),
+            flow: Flow::All,
+            prefilter: Prefilter::default(),
+            output: None,
+            limits: Limits::default(),
+            summary: None,
+            loaders: HashMap::new(),
+            reload_rx: None,
+            rule_items: HashMap::new(),
+            links: HashMap::new(),
         }
     }
 
-    pub async fn run(&mut self, proto: i32, flow: Flow) {
+    pub fn with_match_policy(mut self, policy: MatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Write the matched packets to stdout in the format, see [output::write_packet]
+    pub fn with_output(mut self, format: Format) -> Self {
+        self.output = Some(format);
+        self
+    }
+
+    /// Sum up the captured packets, [Application::run] returns once the count or bytes limit is reached
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self.summary = Some(Summary::default());
+        self
+    }
+
+    pub fn with_sampling(mut self, rates: SampleRates, flow_hash: bool) -> Self {
+        self.sample_rates = rates;
+        self.sample_flow_hash = flow_hash;
+        self
+    }
+
+    /// Apply the rules received from the channel, see [crate::reload::Reloader]
+    pub fn with_reload(
+        mut self,
+        rules: HashMap<String, ConfigItem>,
+        rx: mpsc::Receiver<RuleSet>,
+    ) -> Self {
+        self.rule_items = rules;
+        self.reload_rx = Some(rx);
+        self
+    }
+
+    /// Attach the eBPF programs and handle the packets, only returns once a capture limit is reached
+    pub async fn run(&mut self, prefilter: Prefilter, flow: Flow) {
         info!(
             "start sniff traffic process, flow: {:?}, kernel: {:?}",
             flow,
             util::uname().unwrap().release,
         );
 
-        for iface in self.ifaces.iter() {
-            match flow {
-                Flow::Ingress => {
-                    let tx = self.fork_tx();
-                    let iface = iface.to_owned();
-                    tokio::spawn(async move {
-                        ebpf::load_ingress_sched_cls(iface, proto, tx).await;
-                    });
+        self.flow = flow;
+        self.prefilter = prefilter;
+        // subscribe to the link changes before listing the interfaces, so none is missed
+        let (link_tx, mut link_rx) = mpsc::channel(64);
+        match LinkWatcher::open(None) {
+            Ok(watcher) => {
+                tokio::spawn(watcher.watch(link_tx));
+            }
+            Err(e) => error!("failed to watch the network interfaces by err: {}", e),
+        }
+        self.links.insert(None, attachable_links());
+        self.list_netns_links();
+        self.attach();
+
+        // a rtnetlink socket would keep the namespace alive, so the
+        // interfaces of other namespaces are listed periodically
+        let mut netns_tick = tokio::time::interval(Duration::from_secs(2));
+        let dns_tracker = self.dns_tracker.clone();
+        tokio::spawn(async move {
+            dns_tracker.expire(Duration::from_secs(5)).await;
+        });
+        self.startup_collector().await;
+        if self.collector.is_none() {
+            // the collector sweeps the flows while exporting their metrics
+            let flows = self.flows.clone();
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(Duration::from_secs(5));
+                loop {
+                    tick.tick().await;
+                    flows.sweep();
                 }
-                Flow::Egress => {
-                    let tx = self.fork_tx();
-                    let iface = iface.to_owned();
-                    tokio::spawn(async move {
-                        ebpf::load_egress_sched_cls(iface, proto, tx).await;
-                    });
+            });
+        }
+        loop {
+            tokio::select! {
+                Some(net_pkt) = self.rx.recv() => {
+                    self.handle_packet(net_pkt).await;
+                    if self.summary.as_ref().is_some_and(|s| s.reached(&self.limits)) {
+                        return;
+                    }
                 }
-                Flow::All => {
-                    let (i_tx, e_tx) = (self.fork_tx(), self.fork_tx());
-                    let (i_iface, e_iface) = (iface.to_owned(), iface.to_owned());
-                    tokio::spawn(async move {
-                        ebpf::load_ingress_sched_cls(i_iface, proto, i_tx).await;
-                    });
-                    tokio::spawn(async move {
-                        ebpf::load_egress_sched_cls(e_iface, proto, e_tx).await;
-                    });
+                Some(rules) = recv_reload(&mut self.reload_rx) => self.apply(rules),
+                Some((_, event)) = link_rx.recv() => self.update_link(event),
+                _ = netns_tick.tick() => {
+                    if self.list_netns_links() {
+                        self.attach();
+                    }
                 }
             }
         }
+    }
 
-        self.startup_collector().await;
-        loop {
-            if let Some(net_pkt) = self.rx.recv().await {
-                let addr = match net_pkt.flow {
-                    Flow::Ingress => net_pkt.pkt.src_ip,
-                    Flow::Egress => net_pkt.pkt.dst_ip,
-                    Flow::All => {
-                        /* this branch should not be executed */
-                        continue;
+    async fn handle_packet(&mut self, net_pkt: NetworkPacket) {
+        let addr = match net_pkt.flow {
+            Flow::Ingress => net_pkt.pkt.src_ip,
+            Flow::Egress => net_pkt.pkt.dst_ip,
+            Flow::All => {
+                /* this branch should not be executed */
+                return;
+            }
+        };
+
+        /* handler something */
+        let matched = self.search_and_filter(addr, &net_pkt).await;
+        if !matched.is_empty() {
+            let filters: Vec<&Filter> = matched.iter().map(|f| f.as_ref().as_ref()).collect();
+            self.log_packet(&net_pkt);
+            if let Some(summary) = self.summary.as_mut() {
+                summary.record(&net_pkt);
+            }
+            self.track_connection(&filters, addr, &net_pkt);
+            self.decode_payload(&filters, &net_pkt);
+        }
+    }
+
+    /// Returns the totals of the captured packets, see [Application::with_limits]
+    pub fn summary(&self) -> Option<&Summary> {
+        self.summary.as_ref()
+    }
+
+    /// Detach every eBPF program, the packets left in the ring buffers are dropped
+    pub fn detach(&mut self) {
+        for ((netns, iface, flow), (_, handle)) in self.loaders.drain() {
+            info!(
+                "detach eBPF program from '{}', netns: '{}', flow: {:?}",
+                iface,
+                netns::label(netns.as_ref()),
+                flow
+            );
+            handle.abort();
+        }
+    }
+
+    /// Attach to the created network interfaces and clean up the destroyed ones
+    fn update_link(&mut self, event: LinkEvent) {
+        let links = self.links.entry(None).or_default();
+        let changed = match event {
+            LinkEvent::Added(link) if link.attachable() => links.insert(link.name),
+            LinkEvent::Added(link) => {
+                debug!(
+                    "skip the '{}' {} interface without an Ethernet header",
+                    link.name, link.kind
+                );
+                false
+            }
+            LinkEvent::Removed(iface) => links.remove(&iface),
+        };
+        if changed {
+            self.attach();
+        }
+    }
+
+    /// List the network interfaces of the other namespaces of the selectors,
+    /// returns whether any of them changed.
+    ///
+    /// The interfaces of a removed namespace are gone, as are the namespaces no longer selected.
+    fn list_netns_links(&mut self) -> bool {
+        let namespaces: HashSet<&Netns> = self.ifaces.iter().flat_map(|(n, _)| n).collect();
+        let mut changed = false;
+        self.links.retain(|netns, _| {
+            let keep = netns.as_ref().is_none_or(|n| namespaces.contains(n));
+            changed |= !keep;
+            keep
+        });
+
+        for netns in namespaces {
+            let links = if netns.exists() {
+                netns.enter(attachable_links).unwrap_or_else(|e| {
+                    error!("failed to enter the '{}' netns by err: {}", netns, e);
+                    HashSet::new()
+                })
+            } else {
+                HashSet::new()
+            };
+            if self.links.get(&Some(netns.clone())) != Some(&links) {
+                self.links.insert(Some(netns.clone()), links);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Attach the eBPF programs to the network interfaces matching the selectors, only the
+    /// programs of the removed interfaces or of a changed predicate are detached.
+    fn attach(&mut self) {
+        let directions = match self.flow {
+            Flow::Ingress => vec![Flow::Ingress],
+            Flow::Egress => vec![Flow::Egress],
+            Flow::All => vec![Flow::Ingress, Flow::Egress],
+        };
+
+        let mut wanted = HashMap::new();
+        for (netns, links) in self.links.iter() {
+            for iface in links.iter() {
+                let selected = self
+                    .ifaces
+                    .iter()
+                    .any(|(n, selector)| n == netns && selector.contains(iface));
+                if !selected {
+                    continue;
+                }
+                let rate =
+                    iface::sample_rate(&self.sample_rates, netns::label(netns.as_ref()), iface);
+                for flow in directions.iter() {
+                    let predicate = match flow {
+                        Flow::Ingress => self.prefilter.ingress.clone(),
+                        _ => self.prefilter.egress.clone(),
                     }
-                };
+                    .with_sampling(rate, self.sample_flow_hash);
+                    wanted.insert((netns.clone(), iface.to_owned(), *flow), predicate);
+                }
+            }
+        }
 
-                /* handler something */
-                self.search_and_filter(addr, &net_pkt).await;
+        self.loaders.retain(|key, (predicate, handle)| {
+            if wanted.get(key) == Some(predicate) {
+                return true;
             }
+
+            let (netns, iface, flow) = key;
+            info!(
+                "detach eBPF program from '{}', netns: '{}', flow: {:?}",
+                iface,
+                netns::label(netns.as_ref()),
+                flow
+            );
+            handle.abort();
+            if self.collector.is_some() {
+                metrics::set_attached_iface(iface, netns::label(netns.as_ref()), flow, false);
+            }
+            false
+        });
+
+        for (key, predicate) in wanted {
+            if self.loaders.contains_key(&key) {
+                continue;
+            }
+
+            let tx = self.fork_tx();
+            let (netns, iface, flow) = key.clone();
+            if self.collector.is_some() {
+                metrics::set_attached_iface(&iface, netns::label(netns.as_ref()), &flow, true);
+            }
+            let handle = {
+                let predicate = predicate.clone();
+                match flow {
+                    Flow::Ingress => tokio::spawn(async move {
+                        ebpf::load_ingress_sched_cls(iface, netns, predicate, tx).await;
+                    }),
+                    _ => tokio::spawn(async move {
+                        ebpf::load_egress_sched_cls(iface, netns, predicate, tx).await;
+                    }),
+                }
+            };
+            self.loaders.insert(key, (predicate, handle));
         }
     }
 
+    /// Swap the rules, the counters of the unchanged rules are kept
+    fn apply(&mut self, rules: RuleSet) {
+        info!("apply reloaded rules, flow: {:?}", rules.flow);
+
+        if let Some(collector) = &self.collector {
+            let unchanged: HashSet<String> = rules
+                .items
+                .iter()
+                .filter(|(name, item)| self.rule_items.get(*name) == Some(item))
+                .map(|(name, _)| name.to_owned())
+                .collect();
+            collector.reload(&unchanged, &rules.sample_rates, rules.identities);
+        }
+
+        self.ifaces = rules.ifaces;
+        self.trie = rules.trie;
+        self.empty_filter = Some(rules.empty_filter);
+        self.policy = rules.policy;
+        self.sample_rates = rules.sample_rates;
+        self.sample_flow_hash = rules.sample_flow_hash;
+        self.prefilter = rules.prefilter;
+        self.flow = rules.flow;
+        self.rule_items = rules.items;
+        self.list_netns_links();
+        self.attach();
+    }
+
+    /// Returns the filters of the rules counting the packet according to the [MatchPolicy]
+    ///
+    /// Candidates are the rules whose cidrs contain the address, from the longest
+    /// prefix to the shortest and in configuration order for the same cidr,
+    /// followed by the rules without cidrs.
     #[inline]
-    async fn search_and_filter(&self, addr: Ipv4Addr, net_pkt: &NetworkPacket) {
-        if !self.trie.empty() {
-            let (exit, filter) = self.trie.search(IpAddr::V4(addr));
-            if exit {
-                let (ok, _) = filter.filter(net_pkt);
-                if ok {
-                    self.record_collector(&filter.rule_name(), net_pkt, filter.enable_port())
-                        .await;
-                    self.log_packet(net_pkt);
-                }
-            }
-        } else if let Some(empty_filter) = &self.empty_filter {
-            for filter in empty_filter {
-                let (ok, _) = filter.filter(net_pkt);
-                if ok {
-                    self.log_packet(net_pkt);
+    async fn search_and_filter(
+        &self,
+        addr: Ipv4Addr,
+        net_pkt: &NetworkPacket,
+    ) -> Vec<Arc<Box<Filter>>> {
+        let mut candidates: Vec<Arc<Box<Filter>>> = if !self.trie.empty() {
+            self.trie
+                .search_all(IpAddr::V4(addr))
+                .into_iter()
+                .rev()
+                .flat_map(|filters| filters.iter().cloned())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if let Some(empty_filter) = &self.empty_filter {
+            candidates.extend(empty_filter.iter().cloned());
+        }
+
+        if candidates.is_empty() && self.trie.match_all() {
+            return vec![Arc::new(Box::new(Filter::default_pass_filter()))];
+        }
+
+        if self.policy == MatchPolicy::First {
+            candidates.sort_by_key(|filter| filter.index);
+        }
+
+        let mut matched: Vec<Arc<Box<Filter>>> = Vec::new();
+        for filter in candidates {
+            // a rule with several cidrs containing the address is only counted once
+            if matched.iter().any(|m| m.index == filter.index) {
+                continue;
+            }
+
+            let (ok, _) = filter.filter(net_pkt);
+            if ok {
+                self.record_collector(&filter, net_pkt).await;
+                matched.push(filter);
+                if self.policy != MatchPolicy::All {
                     break;
                 }
             }
-        } else if self.trie.match_all() {
-            self.log_packet(net_pkt);
         }
+
+        matched
     }
 
     /// Output logs in different colors according to traffic direction
     fn log_packet(&self, net_pkt: &NetworkPacket) {
-        if log::log_enabled!(log::Level::Debug) {
+        if let Some(format) = self.output {
+            output::write_packet(format, net_pkt);
+        } else if log::log_enabled!(log::Level::Trace) {
             let pkt_line = format!("{}", net_pkt);
             let output = match net_pkt.flow {
                 Flow::Ingress => pkt_line.bright_green(),
@@ -142,16 +460,153 @@ impl Application {
         }
     }
 
+    /// Track the TCP connection lifecycle, only in server mode
+    ///
+    /// The connection is tracked once, its events are reported to every matched rule.
+    fn track_connection(&self, filters: &[&Filter], peer: Ipv4Addr, net_pkt: &NetworkPacket) {
+        if self.collector.is_none() {
+            return;
+        }
+
+        let update = self
+            .flows
+            .track_tcp(net_pkt, &filters[0].rule_name(), self.sample_flow_hash);
+        for filter in filters {
+            let rule_name = filter.rule_name();
+            for event in update.events.iter() {
+                metrics::inc_tcp_event(
+                    &[&rule_name, &net_pkt.iface, &net_pkt.netns, event.as_str()],
+                    net_pkt.sample_rate as u64,
+                );
+            }
+
+            if update.handshake_rtt.is_some() || update.retransmission {
+                let peer_cidr = filter.peer_cidr(peer);
+                if let Some(rtt) = update.handshake_rtt {
+                    metrics::observe_tcp_handshake_rtt(&[&rule_name, &peer_cidr], rtt);
+                }
+                if update.retransmission {
+                    metrics::inc_tcp_retransmit(
+                        &[&rule_name, &peer_cidr],
+                        net_pkt.sample_rate as u64,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Decode the L7 protocols carried in the packet payload
+    fn decode_payload(&self, filters: &[&Filter], net_pkt: &NetworkPacket) {
+        if let Some(msg) = dns::decode(&net_pkt.pkt) {
+            // a randomly sampled response rarely comes with its sampled query
+            let latency = (net_pkt.sample_rate <= 1 || self.sample_flow_hash)
+                .then(|| self.dns_tracker.track(&net_pkt.pkt, &msg))
+                .flatten();
+            if msg.response && self.collector.is_some() {
+                let qtype = msg.qtype();
+                for filter in filters {
+                    metrics::inc_dns_query(
+                        [&filter.rule_name(), &net_pkt.iface, &net_pkt.netns],
+                        dns::rcode_str(msg.rcode),
+                        &qtype,
+                        latency,
+                        net_pkt.sample_rate as u64,
+                    );
+                }
+            }
+            if log::log_enabled!(log::Level::Debug) {
+                match latency {
+                    Some(latency) => trace!("{msg} latency={:?}", latency),
+                    None => trace!("{msg}"),
+                }
+            }
+        }
+
+        if let Some(hello) = filters
+            .iter()
+            .find_map(|filter| tls::decode(&net_pkt.pkt, &filter.tls_ports))
+        {
+            self.flows.update(net_pkt, |entry| {
+                entry.server_name = hello.server_name.clone();
+                entry.alpn = Some(hello.alpn());
+            });
+            // the server name or ALPN may lie past the captured payload
+            if hello.truncated && self.collector.is_some() {
+                for filter in filters
+                    .iter()
+                    .filter(|filter| filter.tls_ports.contains(&net_pkt.pkt.dst))
+                {
+                    metrics::inc_tls_truncated(
+                        &[&filter.rule_name(), &net_pkt.iface, &net_pkt.netns],
+                        net_pkt.sample_rate as u64,
+                    );
+                }
+            }
+            if log::log_enabled!(log::Level::Debug) {
+                trace!("{hello}");
+            }
+        }
+
+        // sampled packets stand for `sample_rate` packets
+        let weight = net_pkt.sample_rate as u64;
+        let mut http_logged = false;
+        for filter in filters {
+            if let Some(msg) = http::decode(&net_pkt.pkt, &filter.http_ports) {
+                if self.collector.is_some() {
+                    let rule_name = filter.rule_name();
+                    match &msg {
+                        HttpMessage::Request { method, .. } => metrics::inc_http_request(
+                            &[&rule_name, &net_pkt.iface, &net_pkt.netns, method],
+                            weight,
+                        ),
+                        HttpMessage::Response { .. } => metrics::inc_http_response(
+                            &[
+                                &rule_name,
+                                &net_pkt.iface,
+                                &net_pkt.netns,
+                                &msg.status_class().unwrap_or_default(),
+                            ],
+                            weight,
+                        ),
+                    }
+                }
+                if !http_logged && log::log_enabled!(log::Level::Debug) {
+                    trace!("{msg}");
+                    http_logged = true;
+                }
+            }
+
+            if filter.sni_label && self.collector.is_some() {
+                if let Some(server_name) = self.flows.get(net_pkt).and_then(|e| e.server_name) {
+                    metrics::add_tls_bytes(
+                        &[
+                            &filter.rule_name(),
+                            net_pkt.traffic(),
+                            &net_pkt.iface,
+                            &net_pkt.netns,
+                            &server_name,
+                        ],
+                        net_pkt.pkt.length as u64 * weight,
+                    );
+                }
+            }
+        }
+    }
+
     /// record packet information to collector, if set
-    async fn record_collector(
-        &self,
-        rule_name: &String,
-        net_pkt: &NetworkPacket,
-        enable_port: bool,
-    ) {
+    async fn record_collector(&self, filter: &Filter, net_pkt: &NetworkPacket) {
         if let Some(collector) = &self.collector {
-            let identity = collector::netpkt_to_identity(rule_name, enable_port, net_pkt);
-            collector.add(&identity, net_pkt.pkt.length);
+            let identity = collector::netpkt_to_identity(
+                &filter.rule_name(),
+                filter.enable_port(&net_pkt.flow),
+                net_pkt,
+            );
+            collector.add(
+                &identity,
+                net_pkt.pkt.length,
+                net_pkt.sample_rate,
+                &filter.label_values,
+            );
         }
     }
 
@@ -164,6 +619,10 @@ impl Application {
             tokio::spawn(async move {
                 clone.flush().await;
             });
+            let flows = self.flows.clone();
+            tokio::spawn(async move {
+                flows.expire(Duration::from_secs(5)).await;
+            });
             tokio::spawn(async {
                 metrics::metrics_server().await;
             });
@@ -174,3 +633,108 @@ impl Application {
         self.tx.clone()
     }
 }
+
+/// Returns the network interfaces of the current network namespace the eBPF program can be
+/// attached to, those without an Ethernet header such as tunnels are left out, see [netlink::Link::attachable]
+fn attachable_links() -> HashSet<String> {
+    match netlink::list_links() {
+        Ok(links) => links
+            .into_iter()
+            .filter(|link| {
+                if !link.attachable() {
+                    debug!(
+                        "skip the '{}' {} interface without an Ethernet header",
+                        link.name, link.kind
+                    );
+                }
+                link.attachable()
+            })
+            .map(|link| link.name)
+            .collect(),
+        Err(e) => {
+            error!("failed to list the network interfaces by err: {}", e);
+            HashSet::new()
+        }
+    }
+}
+
+/// Receives the reloaded rules, pending forever without a reload channel
+async fn recv_reload(rx: &mut Option<mpsc::Receiver<RuleSet>>) -> Option<RuleSet> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use network_types::ip::IpProto;
+
+    use super::Application;
+    use crate::{config::Traffic, filter::MatchPolicy, network, reload::RuleSet};
+
+    #[tokio::test]
+    async fn test_match_policy() {
+        let config = r#"
+rules:
+  - {name: org, cidrs: [10.0.0.0/8], inIface: [lo], outIface: [lo]}
+  - {name: team, match: tcp, cidrs: [10.1.0.0/16], inIface: [lo], outIface: [lo]}
+  - {name: ops, cidrs: [10.1.0.0/16, 10.0.0.0/8], inIface: [lo], outIface: [lo]}
+  - {name: any, inIface: [lo], outIface: [lo]}
+"#;
+        let matched = |policy: MatchPolicy, proto: IpProto, addr: [u8; 4]| async move {
+            let rules = RuleSet::build(&Traffic::parse(config, false).unwrap()).unwrap();
+            let app = Application::new(rules.ifaces, rules.trie, Some(rules.empty_filter), None)
+                .with_match_policy(policy);
+            let net_pkt = network::test::packet(([192, 168, 0, 1], 40000), (addr, 443))
+                .with_proto(proto)
+                .build();
+            app.search_and_filter(Ipv4Addr::from(addr), &net_pkt)
+                .await
+                .iter()
+                .map(|filter| filter.rule_name())
+                .collect::<Vec<_>>()
+        };
+        let team = [10, 1, 2, 3];
+
+        // the first rule in configuration order
+        assert_eq!(
+            matched(MatchPolicy::First, IpProto::Tcp, team).await,
+            vec!["org"]
+        );
+
+        // the rule of the longest prefix, in configuration order for the same prefix
+        assert_eq!(
+            matched(MatchPolicy::Longest, IpProto::Tcp, team).await,
+            vec!["team"]
+        );
+        assert_eq!(
+            matched(MatchPolicy::Longest, IpProto::Udp, team).await,
+            vec!["ops"]
+        );
+        assert_eq!(
+            matched(MatchPolicy::Longest, IpProto::Tcp, [10, 2, 0, 1]).await,
+            vec!["org"]
+        );
+
+        // every rule once, from the longest prefix to the rules without cidrs
+        assert_eq!(
+            matched(MatchPolicy::All, IpProto::Tcp, team).await,
+            vec!["team", "ops", "org", "any"]
+        );
+        assert_eq!(
+            matched(MatchPolicy::All, IpProto::Udp, team).await,
+            vec!["ops", "org", "any"]
+        );
+
+        // only the rules without cidrs match other addresses
+        for policy in [MatchPolicy::First, MatchPolicy::Longest, MatchPolicy::All] {
+            assert_eq!(
+                matched(policy, IpProto::Tcp, [192, 168, 1, 1]).await,
+                vec!["any"]
+            );
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+use crate::network::NetworkPacket;
+
+/// The number of endpoints listed by the summary, the busiest first
+const SUMMARY_ENDPOINTS: usize = 20;
+
+/// Limits stopping a CLI capture, like `tcpdump -c`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// stop after the number of captured packets
+    pub count: Option<u64>,
+    /// stop once the captured packets add up to the number of bytes
+    pub bytes: Option<u64>,
+    /// stop after the capture has run for the duration
+    pub duration: Option<Duration>,
+}
+
+/// The totals of the captured packets, printed when the capture stops
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub packets: u64,
+    pub bytes: u64,
+    /// the (packets, bytes) sent or received by each address
+    endpoints: HashMap<Ipv4Addr, (u64, u64)>,
+}
+
+impl Summary {
+    pub fn record(&mut self, net_pkt: &NetworkPacket) {
+        let length = net_pkt.pkt.length as u64;
+        self.packets += 1;
+        self.bytes += length;
+
+        for addr in [net_pkt.pkt.src_ip, net_pkt.pkt.dst_ip] {
+            let (packets, bytes) = self.endpoints.entry(addr).or_default();
+            *packets += 1;
+            *bytes += length;
+        }
+    }
+
+    /// Whether the count or the bytes limit is reached
+    pub fn reached(&self, limits: &Limits) -> bool {
+        limits.count.is_some_and(|count| self.packets >= count)
+            || limits.bytes.is_some_and(|bytes| self.bytes >= bytes)
+    }
+
+    /// Returns the endpoints by bytes then packets in descending order
+    fn endpoints(&self) -> Vec<(Ipv4Addr, u64, u64)> {
+        let mut endpoints: Vec<_> = self
+            .endpoints
+            .iter()
+            .map(|(addr, (packets, bytes))| (*addr, *packets, *bytes))
+            .collect();
+        endpoints.sort_by(|a, b| (b.2, b.1, a.0).cmp(&(a.2, a.1, b.0)));
+        endpoints
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} packets captured, {} bytes", self.packets, self.bytes)?;
+        if self.endpoints.is_empty() {
+            return Ok(());
+        }
+
+        let endpoints = self.endpoints();
+        writeln!(f, "{:<18}{:>10}{:>14}", "ENDPOINT", "PACKETS", "BYTES")?;
+        for (addr, packets, bytes) in endpoints.iter().take(SUMMARY_ENDPOINTS) {
+            writeln!(f, "{:<18}{:>10}{:>14}", addr.to_string(), packets, bytes)?;
+        }
+        if endpoints.len() > SUMMARY_ENDPOINTS {
+            writeln!(
+                f,
+                "... and {} more endpoints",
+                endpoints.len() - SUMMARY_ENDPOINTS
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a number of bytes, with an optional `K`, `M` or `G` suffix of powers of 1024
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("unknown unit in '{}', expected K, M or G", s)),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{}' is not a number of bytes", s))?;
+    if value == 0 {
+        return Err("the number of bytes must be at least 1".to_string());
+    }
+
+    value
+        .checked_mul(unit)
+        .ok_or_else(|| format!("'{}' is too large", s))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::{parse_bytes, Limits, Summary};
+    use crate::network::{self, NetworkPacket};
+
+    fn packet(src: [u8; 4], dst: [u8; 4], length: u16) -> NetworkPacket {
+        network::test::packet((src, 5353), (dst, 53))
+            .with_length(length)
+            .build()
+    }
+
+    #[test]
+    fn test_capture_summary() {
+        let mut summary = Summary::default();
+        let limits = Limits {
+            count: Some(3),
+            bytes: Some(1000),
+            ..Default::default()
+        };
+        summary.record(&packet([10, 0, 0, 1], [1, 1, 1, 1], 100));
+        summary.record(&packet([1, 1, 1, 1], [10, 0, 0, 1], 300));
+        assert!(!summary.reached(&limits));
+        summary.record(&packet([10, 0, 0, 1], [8, 8, 8, 8], 60));
+        assert!(summary.reached(&limits));
+        assert!(!summary.reached(&Limits::default()));
+
+        assert_eq!((summary.packets, summary.bytes), (3, 460));
+        assert_eq!(
+            summary.endpoints(),
+            vec![
+                (Ipv4Addr::new(10, 0, 0, 1), 3, 460),
+                (Ipv4Addr::new(1, 1, 1, 1), 2, 400),
+                (Ipv4Addr::new(8, 8, 8, 8), 1, 60)
+            ]
+        );
+        assert!(summary
+            .to_string()
+            .starts_with("3 packets captured, 460 bytes\n"));
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("1500"), Ok(1500));
+        assert_eq!(parse_bytes("10K"), Ok(10 << 10));
+        assert_eq!(parse_bytes("5MB"), Ok(5 << 20));
+        assert_eq!(parse_bytes("1GiB"), Ok(1 << 30));
+        assert!(parse_bytes("0").is_err());
+        assert!(parse_bytes("10T").is_err());
+        assert!(parse_bytes("K").is_err());
+    }
+}
@@ -1,21 +1,22 @@
-use std::{fmt::Debug, net::Ipv4Addr, sync::Arc};
+use std::{fmt::Debug, net::Ipv4Addr};
 
 use ipnetwork::{IpNetwork, Ipv4Network};
 use log::debug;
 
 #[derive(Debug)]
 struct Node<N> {
-    metadata: Arc<N>,
+    // several rules may list the same cidr, they are kept in insertion order
+    metadata: Vec<N>,
 
     is_last: bool,
     left: Option<Box<Node<N>>>,
     right: Option<Box<Node<N>>>,
 }
 
-impl<N: Default> Default for Node<N> {
+impl<N> Default for Node<N> {
     fn default() -> Self {
         Self {
-            metadata: Arc::new(N::default()),
+            metadata: Vec::new(),
             is_last: false,
             left: None,
             right: None,
@@ -70,6 +71,7 @@ where
         self.match_all
     }
 
+    /// Mount the metadata on the cidr, the metadata already mounted on the same cidr is kept
     pub fn insert(&mut self, addr: ipnetwork::Ipv4Network, metadata: N) {
         let mut tmp = self
             .root
@@ -94,18 +96,20 @@ where
             }
 
             if i == bin.len() - 1 {
-                (tmp.is_last, tmp.metadata) = (true, Arc::new(metadata.clone()))
+                tmp.is_last = true;
+                tmp.metadata.push(metadata.clone());
             }
         }
     }
 
-    pub fn search<T>(&self, addr: T) -> (bool, Arc<N>)
+    /// Returns the metadata mounted on the longest prefix matching the address
+    pub fn search<T>(&self, addr: T) -> (bool, Vec<N>)
     where
         T: Into<IpNetwork>,
     {
         let bin = ipaddr_to_binary(addr);
         if self.root.is_none() {
-            return (false, Arc::new(N::default()));
+            return (false, Vec::new());
         }
 
         let mut tmp = self.root.as_ref();
@@ -131,10 +135,38 @@ where
         if let Some(node) = assume_last {
             (node.is_last, node.metadata.clone())
         } else {
-            (self.match_all, Arc::new(N::default()))
+            (self.match_all, Vec::new())
         }
     }
 
+    /// Returns the metadata of every prefix matching the address,
+    /// ordered from the shortest to the longest prefix.
+    pub fn search_all<T>(&self, addr: T) -> Vec<&[N]>
+    where
+        T: Into<IpNetwork>,
+    {
+        let bin = ipaddr_to_binary(addr);
+        let mut result = Vec::new();
+
+        let mut tmp = self.root.as_ref();
+        for b in bin {
+            if b == Self::BIT_1 {
+                tmp = tmp.unwrap().right.as_ref();
+            }
+            if b == Self::BIT_0 {
+                tmp = tmp.unwrap().left.as_ref();
+            }
+
+            match tmp {
+                Some(node) if node.is_last => result.push(node.metadata.as_slice()),
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        result
+    }
+
     /// Used to report the cidr items of PrefixTree mounts
     pub fn summary(&self) {
         if let Some(root) = self.root.as_deref() {
@@ -194,18 +226,13 @@ where
 // Convert a binary slice to a cidr address
 #[inline]
 fn binary_to_cidr(bin: &[u8]) -> Ipv4Network {
-    let bits = bin.len();
-    let mask = bits as u8; // length represents the mask
+    let mask = bin.len() as u8; // length represents the mask
 
     let mut cidr_vec: Vec<u8> = Vec::with_capacity(32);
     for i in 0..32 {
-        if i >= bits {
-            // when the cidr address segment is less than 32 bits,
-            // we fill it with zeros.
-            cidr_vec.push(48);
-        } else {
-            cidr_vec.push(bin[i]);
-        }
+        // when the cidr address segment is less than 32 bits,
+        // we fill it with zeros.
+        cidr_vec.push(bin.get(i).copied().unwrap_or(48));
     }
 
     // convert the four ranges of the IP address into decimal
@@ -225,7 +252,7 @@ fn binary_to_cidr(bin: &[u8]) -> Ipv4Network {
 
 #[cfg(test)]
 mod test {
-    use std::{net::IpAddr, str::FromStr, sync::Arc};
+    use std::{net::IpAddr, str::FromStr};
 
     use ipnetwork::Ipv4Network;
 
@@ -241,19 +268,55 @@ mod test {
 
         assert_eq!(
             trie.search(IpAddr::from_str("1.0.134.168").unwrap()),
-            (true, Arc::new(101))
+            (true, vec![101])
         );
         assert_eq!(
             trie.search(IpAddr::from_str("1.1.1.254").unwrap()),
-            (true, Arc::new(102))
+            (true, vec![102])
         );
         assert_eq!(
             trie.search(IpAddr::from_str("2.0.1.168").unwrap()),
-            (true, Arc::new(103))
+            (true, vec![103])
         );
         assert_eq!(
             trie.search(IpAddr::from_str("2.0.0.168").unwrap()),
-            (true, Arc::new(104))
+            (true, vec![104])
+        );
+    }
+
+    #[test]
+    fn test_search_all_prefix_trie() {
+        let mut trie = PrefixTree::<i32>::new();
+        trie.insert(Ipv4Network::from_str("10.0.0.0/8").unwrap(), 101);
+        trie.insert(Ipv4Network::from_str("10.1.0.0/16").unwrap(), 102);
+        trie.insert(Ipv4Network::from_str("10.2.0.0/16").unwrap(), 103);
+
+        assert_eq!(
+            trie.search_all(IpAddr::from_str("10.1.2.3").unwrap()),
+            vec![&[101][..], &[102][..]]
+        );
+        assert_eq!(
+            trie.search_all(IpAddr::from_str("10.3.2.3").unwrap()),
+            vec![&[101][..]]
+        );
+        assert!(trie
+            .search_all(IpAddr::from_str("11.1.2.3").unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_cidr_prefix_trie() {
+        let mut trie = PrefixTree::<i32>::new();
+        trie.insert(Ipv4Network::from_str("10.0.0.0/8").unwrap(), 101);
+        trie.insert(Ipv4Network::from_str("10.0.0.0/8").unwrap(), 102);
+
+        assert_eq!(
+            trie.search(IpAddr::from_str("10.1.2.3").unwrap()),
+            (true, vec![101, 102])
+        );
+        assert_eq!(
+            trie.search_all(IpAddr::from_str("10.1.2.3").unwrap()),
+            vec![&[101, 102][..]]
         );
     }
 
@@ -262,22 +325,22 @@ mod test {
         let no_match_trie = PrefixTree::<()>::new();
         assert_eq!(
             no_match_trie.search(IpAddr::from_str("1.0.0.168").unwrap()),
-            (false, Arc::new(()))
+            (false, vec![])
         );
         assert_eq!(
             no_match_trie.search(IpAddr::from_str("2.0.0.168").unwrap()),
-            (false, Arc::new(()))
+            (false, vec![])
         );
 
         let mut match_trie = PrefixTree::<()>::new();
         match_trie.set_match_all();
         assert_eq!(
             match_trie.search(IpAddr::from_str("1.0.0.168").unwrap()),
-            (true, Arc::new(()))
+            (true, vec![])
         );
         assert_eq!(
             match_trie.search(IpAddr::from_str("2.0.0.168").unwrap()),
-            (true, Arc::new(()))
+            (true, vec![])
         );
     }
 }
@@ -1,5 +1,12 @@
+use std::time::Duration;
+
 use clap::{Parser, Subcommand, ValueEnum};
 
+use crate::{
+    capture::{self, Limits},
+    output::Format,
+};
+
 #[derive(Parser)]
 #[command(disable_help_subcommand = true)]
 pub struct Cmd {
@@ -24,14 +31,46 @@ pub struct Cmd {
     )]
     pub flow: Flow,
 
-    /// One or more ifaces to attach. (e.g. --iface lo,eth0...)
+    /// One or more ifaces to attach, by name or selector. (e.g. --iface lo,eth0,'veth*','!lo'...)
     #[arg(short = 'i', value_name = "iface,", global = true)]
     pub ifaces: Vec<String>,
 
+    /// Attach to the ifaces of a network namespace, by name, pid or path. (e.g. --netns 1234)
+    #[arg(long = "netns", value_name = "netns", global = true)]
+    pub netns: Option<String>,
+
     /// Detect traffic matching the given cidr. If not set, all traffic will be matched.
     #[arg(short = 'c', value_name = "cidr,", global = true)]
     pub cidrs: Vec<String>,
 
+    /// Extract the TLS server name from ClientHello messages sent to the given ports.
+    #[arg(
+        long = "tls-ports",
+        value_name = "port,",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub tls_ports: Vec<u16>,
+
+    /// Decode plain-text HTTP/1.x requests and responses on the given ports.
+    #[arg(
+        long = "http-ports",
+        value_name = "port,",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub http_ports: Vec<u16>,
+
+    /// Format of the captured packets written to stdout.
+    #[arg(
+        long = "output",
+        value_name = "FORMAT",
+        default_value_t = Format::Text,
+        value_enum,
+        global = true
+    )]
+    pub output: Format,
+
     #[command(subcommand)]
     pub sub_cmd: SubCmd,
 }
@@ -51,29 +90,78 @@ pub enum Flow {
 #[derive(Subcommand, Clone)]
 pub enum SubCmd {
     /// Detect all types of (TCP/UDP) traffic
-    All,
+    All(Sniff),
 
     /// Detect TCP type traffic
-    Tcp,
+    Tcp(Sniff),
 
     /// Detect UDP type traffic
-    Udp,
+    Udp(Sniff),
 
     /// Check whether the sniff ebpf program can be mounted correctly
     Check,
 
     /// Running sniff ebpf program as server
     Run(Run),
+
+    /// Validate the configuration file without attaching the sniff ebpf program
+    Validate(Validate),
+
+    /// List the network interfaces and whether the sniff ebpf program can be attached to them
+    Interfaces,
 }
 
 impl SubCmd {
     pub fn proto_num(&self) -> i32 {
         match self {
-            SubCmd::Tcp => 1,
-            SubCmd::Udp => 2,
+            SubCmd::Tcp(_) => 1,
+            SubCmd::Udp(_) => 2,
             _ => 0,
         }
     }
+
+    /// Returns the capture limits given to the sniff subcommands
+    pub fn limits(&self) -> Limits {
+        match self {
+            SubCmd::All(sniff) | SubCmd::Tcp(sniff) | SubCmd::Udp(sniff) => Limits {
+                count: sniff.count,
+                bytes: sniff.until_bytes,
+                duration: sniff.duration,
+            },
+            _ => Limits::default(),
+        }
+    }
+
+    /// Returns the filter expression given to the sniff subcommands, if any
+    pub fn expression(&self) -> Option<String> {
+        match self {
+            SubCmd::All(sniff) | SubCmd::Tcp(sniff) | SubCmd::Udp(sniff)
+                if !sniff.expression.is_empty() =>
+            {
+                Some(sniff.expression.join(" "))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Parser, Clone)]
+pub struct Sniff {
+    /// Stop after capturing the number of packets.
+    #[arg(long = "count", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub count: Option<u64>,
+
+    /// Stop after capturing for the duration. (e.g. 30s, 5m)
+    #[arg(long = "duration", value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// Stop once the captured packets add up to the bytes. (e.g. 1500, 10K, 5M)
+    #[arg(long = "until-bytes", value_name = "BYTES", value_parser = capture::parse_bytes)]
+    pub until_bytes: Option<u64>,
+
+    /// Only detect traffic matching the filter expression. (e.g. tcp and dst port 443 and not net 10.0.0.0/8)
+    #[arg(value_name = "EXPRESSION", trailing_var_arg = true)]
+    pub expression: Vec<String>,
 }
 
 #[derive(Parser, Clone)]
@@ -81,3 +169,13 @@ pub struct Run {
     /// Specify the configuration file to be loaded by sniff
     pub config: String,
 }
+
+#[derive(Parser, Clone)]
+pub struct Validate {
+    /// Specify the configuration file to be validated
+    pub config: String,
+
+    /// Skip checking that the network interfaces of the rules exist on the current host
+    #[arg(long = "skip-iface-check")]
+    pub skip_iface_check: bool,
+}
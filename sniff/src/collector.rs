@@ -1,50 +1,72 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, RwLock,
     },
     time::Duration,
 };
 
 use log::debug;
 use network_types::ip::IpProto;
+use prometheus::Histogram;
 use sniff_common::Flow;
 
 use crate::{
     filter::Filter,
-    metrics,
+    iface::{self, SampleRates},
+    metrics, netns,
     network::{NetworkPacket, Proto},
 };
 
 type DataMap = HashMap<String, PacketCollector>;
 
+/// The identities of the collectors with the const label values of their rule
+pub type Identities = Vec<(String, Option<Arc<HashMap<String, String>>>)>;
+
 /// Collects the network packet size for each rule
+///
+/// Identities of single ports are registered up front, identities of ports
+/// matched by a port range are registered on their first packet.
 #[derive(Debug)]
 pub struct CollectorMap {
     export_interval: Duration,
-    packet_data: DataMap,
+    packet_data: RwLock<DataMap>,
 }
 
 #[derive(Debug)]
 struct PacketCollector {
     data_total: AtomicU64,
     label_values: Option<Arc<HashMap<String, String>>>,
+    size_histogram: Option<Histogram>,
 }
 
 impl PacketCollector {
-    pub fn new(label_values: Option<Arc<HashMap<String, String>>>) -> Self {
+    pub fn new(identity_line: &str, label_values: Option<Arc<HashMap<String, String>>>) -> Self {
+        let size_histogram =
+            metrics::packet_size_histogram(&series_label_values(identity_line, &label_values));
+
         Self {
             data_total: AtomicU64::new(0),
             label_values,
+            size_histogram,
         }
     }
 
-    pub fn set(&self, data_tol: u16) {
+    /// Record a packet of `data_tol` bytes standing for `sample_rate` packets.
+    ///
+    /// The size histogram keeps the distribution of the sampled packets.
+    pub fn set(&self, data_tol: u16, sample_rate: u32) {
         // "Acquire" is used here to avoid reordering subsequent operations.
         let val = self.data_total.load(Ordering::Acquire);
-        self.data_total
-            .store(val + (data_tol as u64), Ordering::Relaxed);
+        self.data_total.store(
+            val + (data_tol as u64) * (sample_rate as u64),
+            Ordering::Relaxed,
+        );
+
+        if let Some(histogram) = &self.size_histogram {
+            histogram.observe(data_tol as f64);
+        }
     }
 
     pub fn clear(&self) {
@@ -60,19 +82,34 @@ impl CollectorMap {
     pub fn new(internal: Duration) -> Self {
         Self {
             export_interval: internal,
-            packet_data: HashMap::new(),
+            packet_data: RwLock::new(HashMap::new()),
         }
     }
 
     pub fn insert(&mut self, name: String, label_values: Option<Arc<HashMap<String, String>>>) {
-        self.packet_data
-            .insert(name, PacketCollector::new(label_values));
+        let collector = PacketCollector::new(&name, label_values);
+        self.packet_data.get_mut().unwrap().insert(name, collector);
     }
 
-    pub fn add(&self, name: &String, data_tol: u16) {
-        if let Some(c) = self.packet_data.get(name) {
-            c.set(data_tol);
+    pub fn add(
+        &self,
+        name: &String,
+        data_tol: u16,
+        sample_rate: u32,
+        label_values: &Arc<HashMap<String, String>>,
+    ) {
+        if let Some(c) = self.packet_data.read().unwrap().get(name) {
+            c.set(data_tol, sample_rate);
+            return;
         }
+
+        let label_values = (!label_values.is_empty()).then(|| label_values.clone());
+        self.packet_data
+            .write()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_insert_with(|| PacketCollector::new(name, label_values))
+            .set(data_tol, sample_rate);
     }
 
     pub async fn flush(&self) {
@@ -81,23 +118,68 @@ impl CollectorMap {
             tick.tick().await;
             debug!("trigger collector flush to metrics cycle");
 
-            self.packet_data.iter().for_each(|(identity_line, item)| {
-                let mut meta_kvs = identity_to_label_values(identity_line);
-                if let Some(label_values) = &item.label_values {
-                    label_values.iter().for_each(|(k, v)| {
-                        meta_kvs.insert(k.as_str(), v.as_str());
-                    });
-                };
-
+            let packet_data = self.packet_data.read().unwrap();
+            packet_data.iter().for_each(|(identity_line, item)| {
+                let meta_kvs = series_label_values(identity_line, &item.label_values);
                 metrics::set_gauge(item.get() as i64, &meta_kvs);
                 item.clear();
             });
         }
     }
+
+    /// Swap the collectors to the identities of the reloaded rules.
+    ///
+    /// Collectors of the unchanged rules keep their counters, unless the sample rate of
+    /// their network interface changed. The series of the other collectors are removed.
+    pub fn reload(
+        &self,
+        unchanged_rules: &HashSet<String>,
+        sample_rates: &SampleRates,
+        identities: Identities,
+    ) {
+        let mut packet_data = self.packet_data.write().unwrap();
+        packet_data.retain(|identity_line, item| {
+            let values = identity_to_label_values(identity_line);
+            let rate = iface::sample_rate(sample_rates, values["netns"], values["network_iface"]);
+            if unchanged_rules.contains(values["rule_name"])
+                && values["sample_rate"] == rate.to_string()
+            {
+                return true;
+            }
+
+            debug!("remove metrics identity: [{}]", identity_line);
+            metrics::remove_packet_series(&series_label_values(identity_line, &item.label_values));
+            false
+        });
+
+        for (identity, label_values) in identities {
+            packet_data
+                .entry(identity)
+                .or_insert_with_key(|identity| PacketCollector::new(identity, label_values));
+        }
+    }
+}
+
+/// Returns the label values of the metrics series of the identity
+fn series_label_values<'a>(
+    identity_line: &'a str,
+    label_values: &'a Option<Arc<HashMap<String, String>>>,
+) -> HashMap<&'a str, &'a str> {
+    let mut meta_kvs = identity_to_label_values(identity_line);
+    if let Some(label_values) = label_values {
+        label_values.iter().for_each(|(k, v)| {
+            meta_kvs.insert(k.as_str(), v.as_str());
+        });
+    };
+
+    meta_kvs
 }
 
 pub fn identity_to_label_values(identity_line: &str) -> HashMap<&str, &str> {
-    let values: Vec<&str> = identity_line.split("_").collect();
+    // the network namespace is last, its name or path may contain '_'
+    let values: Vec<&str> = identity_line
+        .splitn(metrics::PACKET_TOL_LV_CAP, "_")
+        .collect();
     let mut result = HashMap::with_capacity(metrics::PACKET_TOL_LV_CAP);
 
     result.insert("rule_name", values[0]);
@@ -105,6 +187,8 @@ pub fn identity_to_label_values(identity_line: &str) -> HashMap<&str, &str> {
     result.insert("protocol", values[2]);
     result.insert("network_iface", values[3]);
     result.insert("port", values[4]);
+    result.insert("sample_rate", values[5]);
+    result.insert("netns", values[6]);
 
     result
 }
@@ -118,17 +202,16 @@ pub fn netpkt_to_identity(
     enable_port: bool,
     net_pkt: &NetworkPacket,
 ) -> String {
-    let (traffic, port) = match &net_pkt.flow {
-        Flow::Ingress => ("ingress", {
-            if enable_port {
-                net_pkt.pkt.dst.to_string()
-            } else {
-                "undefine".to_string()
-            }
-        }),
-        Flow::Egress => ("egress", "unsupport".to_string()),
+    let traffic = match &net_pkt.flow {
+        Flow::Ingress => "ingress",
+        Flow::Egress => "egress",
         Flow::All => panic!("should be no bidirectional traffic type"),
     };
+    let port = if enable_port {
+        net_pkt.pkt.dst.to_string()
+    } else {
+        "undefine".to_string()
+    };
 
     let proto = match &net_pkt.pkt.proto {
         IpProto::Tcp => "tcp",
@@ -140,8 +223,8 @@ pub fn netpkt_to_identity(
     };
 
     format!(
-        "{}_{}_{}_{}_{}",
-        rule_name, traffic, proto, &net_pkt.iface, port
+        "{}_{}_{}_{}_{}_{}_{}",
+        rule_name, traffic, proto, &net_pkt.iface, port, net_pkt.sample_rate, &net_pkt.netns
     )
 }
 
@@ -150,9 +233,9 @@ pub fn netpkt_to_identity(
 ///
 /// The unique identifier can offload a lot of metadata to find its associated [PacketCollector] in [Collector]
 ///
-/// * format it follows is: `<rule_name>_<flow>_<protocol>_<iface>_<port>`
-/// * final effect demo is as follows: `demo1_ingress_tcp_enp1s0_undefine`
-pub fn filter_to_identity(filter: &Filter) -> Vec<String> {
+/// * format it follows is: `<rule_name>_<flow>_<protocol>_<iface>_<port>_<sample_rate>_<netns>`
+/// * final effect demo is as follows: `demo1_ingress_tcp_enp1s0_undefine_1_`
+pub fn filter_to_identity(filter: &Filter, sample_rates: &SampleRates) -> Vec<String> {
     let mut identitys = Vec::new();
 
     let must_proto = match filter.protocol {
@@ -161,46 +244,160 @@ pub fn filter_to_identity(filter: &Filter) -> Vec<String> {
         Proto::ALL => vec!["tcp", "udp"],
     };
 
-    for iface in &filter.in_iface_filter {
-        if !filter.in_port_filter.is_empty() {
-            for port in &filter.in_port_filter {
+    let netns = netns::label(filter.netns.as_ref());
+    let directions = [
+        ("ingress", &filter.in_iface_filter, &filter.in_port_filter),
+        ("egress", &filter.out_iface_filter, &filter.out_port_filter),
+    ];
+    for (traffic, ifaces, ports) in directions {
+        // identities of port ranges are registered on their first packet
+        let ports: Vec<String> = if ports.is_empty() {
+            vec!["undefine".to_string()]
+        } else {
+            ports.singles().map(|p| p.to_string()).collect()
+        };
+
+        // identities of interfaces matched by a pattern are registered on their first packet
+        for iface in ifaces.names() {
+            for port in &ports {
                 for proto in &must_proto {
                     identitys.push(format!(
-                        "{}_{}_{}_{}_{}",
+                        "{}_{}_{}_{}_{}_{}_{}",
                         filter.rule_name(),
-                        "ingress",
+                        traffic,
                         proto,
                         iface,
-                        port
+                        port,
+                        iface::sample_rate(sample_rates, netns, iface),
+                        netns
                     ));
                 }
             }
-        } else {
-            for proto in &must_proto {
-                identitys.push(format!(
-                    "{}_{}_{}_{}_{}",
-                    filter.rule_name(),
-                    "ingress",
-                    proto,
-                    iface,
-                    "undefine"
-                ));
-            }
         }
     }
 
-    for iface in &filter.out_iface_filter {
-        for proto in &must_proto {
-            identitys.push(format!(
-                "{}_{}_{}_{}_{}",
-                filter.rule_name(),
-                "egress",
-                proto,
-                iface,
-                "unsupport",
-            ));
-        }
+    identitys
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::CollectorMap;
+    use crate::{config::Traffic, metrics};
+
+    #[test]
+    fn test_reload_collector() {
+        let mut collector = CollectorMap::new(Duration::from_secs(1));
+        collector.insert("web_ingress_tcp_lo_80_1_".to_string(), None);
+        collector.insert("dns_egress_udp_lo_53_1_".to_string(), None);
+        let empty = Arc::new(HashMap::new());
+        collector.add(&"web_ingress_tcp_lo_80_1_".to_string(), 100, 1, &empty);
+        collector.add(&"dns_egress_udp_lo_53_1_".to_string(), 60, 1, &empty);
+
+        // the dns rule changed, the web rule is kept
+        let unchanged = HashSet::from(["web".to_string()]);
+        collector.reload(
+            &unchanged,
+            &HashMap::new(),
+            vec![
+                ("web_ingress_tcp_lo_80_1_".to_string(), None),
+                ("dns_egress_udp_lo_5353_1_".to_string(), None),
+            ],
+        );
+
+        let packet_data = collector.packet_data.read().unwrap();
+        assert_eq!(packet_data.len(), 2);
+        assert_eq!(packet_data["web_ingress_tcp_lo_80_1_"].get(), 100);
+        assert_eq!(packet_data["dns_egress_udp_lo_5353_1_"].get(), 0);
+        drop(packet_data);
+
+        // a changed sample rate resets the collectors of the network interface
+        let rates = HashMap::from([((String::new(), "lo".to_string()), 10)]);
+        collector.reload(
+            &unchanged,
+            &rates,
+            vec![("web_ingress_tcp_lo_80_10_".to_string(), None)],
+        );
+        let packet_data = collector.packet_data.read().unwrap();
+        assert_eq!(packet_data.len(), 1);
+        assert_eq!(packet_data["web_ingress_tcp_lo_80_10_"].get(), 0);
     }
 
-    identitys
+    #[test]
+    fn test_packet_size_histogram() {
+        let config = "packetSizeBuckets: [64, 512, 1500]\nrules: []\n";
+        let traffic = Traffic::parse(config, false).unwrap();
+        let buckets = traffic.packet_size_buckets();
+        assert_eq!(buckets, Some(vec![64.0, 512.0, 1500.0]));
+        let config = "packetSizeBuckets: []\nrules: []\n";
+        assert!(Traffic::parse(config, false)
+            .unwrap()
+            .packet_size_buckets()
+            .is_none());
+        metrics::build_metrics(vec![], buckets).unwrap();
+
+        let mut collector = CollectorMap::new(Duration::from_secs(1));
+        collector.insert("sized_ingress_tcp_lo_80_1_".to_string(), None);
+        collector.insert("dropped_ingress_tcp_lo_80_1_".to_string(), None);
+        let empty = Arc::new(HashMap::new());
+        for size in [60, 100, 1000] {
+            collector.add(&"sized_ingress_tcp_lo_80_1_".to_string(), size, 1, &empty);
+        }
+        // the histogram keeps the sampled sizes, the total is scaled by the sample rate
+        collector.add(&"dropped_ingress_tcp_lo_80_1_".to_string(), 100, 10, &empty);
+
+        let packet_data = collector.packet_data.read().unwrap();
+        let histogram = packet_data["sized_ingress_tcp_lo_80_1_"]
+            .size_histogram
+            .as_ref()
+            .unwrap();
+        assert_eq!(histogram.get_sample_count(), 3);
+        assert_eq!(histogram.get_sample_sum(), 1160.0);
+        let dropped = &packet_data["dropped_ingress_tcp_lo_80_1_"];
+        assert_eq!(
+            dropped.size_histogram.as_ref().unwrap().get_sample_count(),
+            1
+        );
+        assert_eq!(dropped.get(), 1000);
+        drop(packet_data);
+
+        let series = |rule_name: &str| {
+            prometheus::gather()
+                .iter()
+                .filter(|family| family.get_name() == "network_packet_size_bytes")
+                .flat_map(|family| family.get_metric())
+                .filter(|metric| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .any(|l| l.get_name() == "rule_name" && l.get_value() == rule_name)
+                })
+                .map(|metric| {
+                    let histogram = metric.get_histogram();
+                    let buckets: Vec<u64> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|b| b.get_cumulative_count())
+                        .collect();
+                    (histogram.get_sample_count(), buckets)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(series("sized"), vec![(3, vec![1, 2, 3])]);
+        assert_eq!(series("dropped").len(), 1);
+
+        // the series of the dropped identities are removed on reload
+        collector.reload(
+            &HashSet::from(["sized".to_string()]),
+            &HashMap::new(),
+            vec![("sized_ingress_tcp_lo_80_1_".to_string(), None)],
+        );
+        assert_eq!(series("sized"), vec![(3, vec![1, 2, 3])]);
+        assert!(series("dropped").is_empty());
+    }
 }
@@ -1,18 +1,29 @@
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
-    io,
-    path::Path,
+    fs, io,
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
 use anyhow::{anyhow, Result};
+use log::warn;
 use serde::Deserialize;
 use sniff_common::Flow;
 
-use crate::{network, util};
+use crate::{
+    diagnostic::{ConfigError, ConfigWarning},
+    expr::Expr,
+    filter::MatchPolicy,
+    iface::{IfaceSelector, IfaceSet, SampleRates},
+    netns::{self, Netns},
+    network,
+    port::PortRange,
+    template::{self, Rendered},
+    util,
+};
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Traffic {
     #[serde(
         rename(deserialize = "exportInterval"),
@@ -25,6 +36,53 @@ pub struct Traffic {
 
     #[serde(rename(deserialize = "rules"))]
     pub rules: Option<Vec<ConfigItem>>,
+
+    #[serde(rename(deserialize = "packetSizeBuckets"))]
+    pub packet_size_buckets: Option<Vec<f64>>,
+
+    #[serde(rename(deserialize = "matchPolicy"), default)]
+    pub match_policy: MatchPolicy,
+
+    #[serde(default)]
+    pub sampling: Sampling,
+
+    /// rule files and directories of rule files, relative to the configuration file
+    pub include: Option<Vec<String>>,
+
+    /// the configuration file, the included files and directories
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+/// A rule file included by the configuration
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    rules: Vec<ConfigItem>,
+}
+
+/// A configuration read from its files, with the diagnostics of its errors and warnings
+#[derive(Debug, Default)]
+pub struct Report {
+    /// `None` if any error was found
+    pub traffic: Option<Traffic>,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Sampling performed by the eBPF classifier of each network interface
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sampling {
+    /// sample whole flows rather than single packets
+    #[serde(rename(deserialize = "flowHash"), default)]
+    pub flow_hash: bool,
+
+    /// sample rates of the network interfaces of the namespace of netsniff,
+    /// taking precedence over the rates of the rules
+    #[serde(default)]
+    pub ifaces: HashMap<String, u32>,
 }
 
 impl Traffic {
@@ -32,66 +90,309 @@ impl Traffic {
     where
         P: AsRef<Path>,
     {
-        Self::load_config(File::options().read(true).open(path.as_ref())?)
+        let report = Self::read(path.as_ref(), true);
+        report.warnings.iter().for_each(|w| warn!("{}", w));
+        match report.traffic {
+            Some(traffic) => Ok(traffic),
+            None => Err(anyhow!(report.errors.join("\n"))),
+        }
+    }
+
+    /// Reads and checks the configuration file and the rule files it includes.
+    ///
+    /// The included directories are read in the `rules.d` mode: every `.yaml`/`.yml`
+    /// file of the directory is included, in the order of their names.
+    pub fn read(path: &Path, lookup_ifaces: bool) -> Report {
+        let mut report = Report::default();
+        let main = path.display().to_string();
+        let source = match read_source(path) {
+            Ok(source) => source,
+            Err(e) => {
+                report.errors.push(e);
+                return report;
+            }
+        };
+        let mut traffic: Self = match serde_yaml::from_str(&source.text) {
+            Ok(traffic) => traffic,
+            Err(e) => {
+                report
+                    .errors
+                    .push(ConfigError::Syntax(e).diagnostic(&main, &source));
+                return report;
+            }
+        };
+        traffic.files.push(path.to_path_buf());
+        traffic
+            .rules
+            .iter_mut()
+            .flatten()
+            .for_each(|item| item.source = Some(main.clone()));
+
+        let mut sources = HashMap::from([(main.clone(), source)]);
+        let base = path.parent().unwrap_or(Path::new("."));
+        for include in traffic.include.clone().into_iter().flatten() {
+            let files = match include_files(&base.join(&include)) {
+                Ok(files) => files,
+                Err(e) => {
+                    let error = ConfigError::Include {
+                        path: include,
+                        reason: e.to_string(),
+                    };
+                    report.errors.push(error.diagnostic(&main, &sources[&main]));
+                    continue;
+                }
+            };
+            if base.join(&include).is_dir() {
+                traffic.files.push(base.join(&include));
+            }
+
+            for file in files {
+                let name = file.display().to_string();
+                let source = match read_source(&file) {
+                    Ok(source) => source,
+                    Err(e) => {
+                        report.errors.push(e);
+                        continue;
+                    }
+                };
+                traffic.files.push(file);
+
+                // a file of comments only has no rules
+                let rule_file = match serde_yaml::from_str::<Option<RuleFile>>(&source.text) {
+                    Ok(rule_file) => rule_file.map(|f| f.rules).unwrap_or_default(),
+                    Err(e) => {
+                        report
+                            .errors
+                            .push(ConfigError::Syntax(e).diagnostic(&name, &source));
+                        continue;
+                    }
+                };
+                let rules = traffic.rules.get_or_insert_with(Vec::new);
+                for mut item in rule_file {
+                    item.source = Some(name.clone());
+                    rules.push(item);
+                }
+                sources.insert(name, source);
+            }
+        }
+
+        // the diagnostics refer to the file of the rule
+        let file_of = |rules: &Option<Vec<ConfigItem>>, rule: Option<&str>| -> String {
+            rules
+                .iter()
+                .flatten()
+                .find(|item| Some(item.name.as_str()) == rule)
+                .and_then(|item| item.source.clone())
+                .unwrap_or(main.clone())
+        };
+        for e in traffic.validate(lookup_ifaces) {
+            let file = match &e {
+                ConfigError::DuplicateRule { source, .. } => source.clone().unwrap_or(main.clone()),
+                _ => file_of(&traffic.rules, e.rule()),
+            };
+            report.errors.push(e.diagnostic(&file, &sources[&file]));
+        }
+        for w in traffic.warnings() {
+            let file = file_of(&traffic.rules, Some(w.rule()));
+            report.warnings.push(w.diagnostic(&file, &sources[&file]));
+        }
+
+        if report.errors.is_empty() {
+            report.traffic = Some(traffic);
+        }
+        report
     }
 
-    pub fn load_config<R>(reader: R) -> Result<Self>
+    pub fn load_config<R>(mut reader: R) -> Result<Self>
     where
         R: io::Read,
     {
-        let mut traffic: Self = serde_yaml::from_reader(reader)?;
-        traffic.check()?;
+        let mut source = String::new();
+        reader.read_to_string(&mut source)?;
+        Self::parse(&source, true).map_err(|errors| {
+            let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            anyhow!(messages.join("\n"))
+        })
+    }
+
+    /// Parses and checks the configuration, returning every error found.
+    ///
+    /// * `lookup_ifaces`: check that the network interfaces of the rules exist on the current host
+    pub fn parse(source: &str, lookup_ifaces: bool) -> Result<Self, Vec<ConfigError>> {
+        let source = template::render(source, template::lookup)
+            .map_err(|e| vec![ConfigError::Template(e)])?;
+        let mut traffic: Self =
+            serde_yaml::from_str(&source.text).map_err(|e| vec![ConfigError::Syntax(e)])?;
+        let errors = traffic.validate(lookup_ifaces);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        traffic.warnings().iter().for_each(|w| warn!("{}", w));
 
         Ok(traffic)
     }
 
     pub fn check(&mut self) -> Result<()> {
-        match self.rules.as_ref() {
-            Some(_) => self.check_config(),
+        match self.validate(true).into_iter().next() {
+            Some(e) => Err(e.into()),
             None => Ok(()),
         }
     }
 
-    fn check_config<'a>(&'a mut self) -> Result<()> {
-        let config = self.rules.as_mut().unwrap();
-        let mut lookup_iface: HashSet<&'a str> = HashSet::new();
+    /// Runs every check of the configuration and returns all the errors found,
+    /// the rules are completed with the unset constLabels.
+    pub fn validate(&mut self, lookup_ifaces: bool) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+
+        if humantime::parse_duration(&self.export_interval).is_err() {
+            errors.push(ConfigError::ExportInterval(self.export_interval.clone()));
+        }
+
+        // check if the histogram buckets are valid
+        if let Some(buckets) = &self.packet_size_buckets {
+            if buckets.windows(2).any(|w| w[0] >= w[1]) || buckets.iter().any(|b| *b <= 0.0) {
+                errors.push(ConfigError::PacketSizeBuckets(buckets.clone()));
+            }
+        }
+
+        for (iface, _) in self.sampling.ifaces.iter().filter(|(_, rate)| **rate == 0) {
+            errors.push(ConfigError::IfaceSampleRate {
+                iface: iface.to_owned(),
+            });
+        }
+        for iface in self.sampling.ifaces.keys() {
+            if let Err(e) = iface.parse::<IfaceSelector>() {
+                errors.push(ConfigError::IfacePattern {
+                    rule: None,
+                    pattern: iface.to_owned(),
+                    reason: e.to_string(),
+                });
+            }
+        }
+
+        let host_ifaces = if lookup_ifaces {
+            Some(util::local_interfaces())
+        } else {
+            None
+        };
+        // the interfaces by network namespace, `None` without the privileges to enter it
+        let mut netns_ifaces: HashMap<String, Option<HashSet<String>>> = HashMap::new();
         let labels_map: HashSet<String> = if let Some(labels) = &self.const_labels {
             labels.iter().map(|k| k.to_owned()).collect()
         } else {
             HashSet::new()
         };
+        let mut rule_names: HashMap<String, Option<String>> = HashMap::new();
+        // the number of rules by name and file, to locate the duplicates of the same file
+        let mut definitions: HashMap<(String, Option<String>), usize> = HashMap::new();
 
-        for item in config.iter_mut() {
-            // check if the network interface exists
-            if let Some(ifaces) = item.in_iface.as_ref() {
-                ifaces.iter().for_each(|i| {
-                    lookup_iface.insert(i);
-                });
+        for item in self.rules.iter_mut().flatten() {
+            let rule = item.name.clone();
+
+            // rule names are unique across the configuration and its included files
+            let nth = definitions
+                .entry((rule.clone(), item.source.clone()))
+                .or_default();
+            match rule_names.get(&rule) {
+                Some(first) => errors.push(ConfigError::DuplicateRule {
+                    rule: rule.clone(),
+                    source: item.source.clone(),
+                    first: first.clone(),
+                    nth: *nth,
+                }),
+                None => {
+                    rule_names.insert(rule.clone(), item.source.clone());
+                }
             }
-            if let Some(ifaces) = item.out_iface.as_ref() {
-                ifaces.iter().for_each(|i| {
-                    lookup_iface.insert(i);
-                });
+            *nth += 1;
+
+            // check if the network namespace exists, its interfaces are checked if it can be entered
+            let local_ifaces = match item.netns.as_deref().map(str::parse::<Netns>) {
+                None => host_ifaces.as_ref(),
+                Some(Ok(netns)) => {
+                    if lookup_ifaces && !netns.exists() {
+                        errors.push(ConfigError::Netns {
+                            rule: rule.clone(),
+                            netns: netns.to_string(),
+                            reason: "no such network namespace".to_string(),
+                        });
+                    }
+                    netns_ifaces
+                        .entry(netns.to_string())
+                        .or_insert_with(|| {
+                            lookup_ifaces
+                                .then(|| netns.enter(util::local_interfaces).ok())
+                                .flatten()
+                        })
+                        .as_ref()
+                }
+                Some(Err(_)) => {
+                    errors.push(ConfigError::Netns {
+                        rule: rule.clone(),
+                        netns: item.netns.clone().unwrap_or_default(),
+                        reason: "expected a name, a pid or a path".to_string(),
+                    });
+                    None
+                }
+            };
+
+            // check if the network interface selector is valid, and the named interface exists.
+            // the interfaces matched by a pattern may be created later
+            for iface in [&item.in_iface, &item.out_iface, &item.exclude_iface]
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                match iface.parse::<IfaceSelector>() {
+                    Ok(selector) => {
+                        if let Some(local_ifaces) = local_ifaces {
+                            if selector.is_name() && !local_ifaces.contains(iface) {
+                                errors.push(ConfigError::IfaceNotFound {
+                                    rule: rule.clone(),
+                                    iface: iface.to_owned(),
+                                });
+                            }
+                        }
+                    }
+                    Err(e) => errors.push(ConfigError::IfacePattern {
+                        rule: Some(rule.clone()),
+                        pattern: iface.to_owned(),
+                        reason: e.to_string(),
+                    }),
+                }
             }
 
             // check if the cidr validate
-            if let Some(cidrs) = item.cidrs.as_ref() {
+            for cidrs in [
+                &item.cidrs,
+                &item.src_cidrs,
+                &item.dst_cidrs,
+                &item.exclude_cidrs,
+            ]
+            .into_iter()
+            .flatten()
+            {
                 for cidr in cidrs {
-                    match ipnetwork::Ipv4Network::from_str(cidr) {
-                        Ok(ipv4) => {
-                            if ipv4.prefix() == 0x20 {
-                                return Err(anyhow!(
-                                    "current cidr: '{}' mask='{}', please provide a valid mask value",
-                                    cidr,
-                                    ipv4.mask()
-                                ));
-                            }
-                        }
-                        Err(e) => return Err(anyhow!("failed to parse cidr='{}' by {}", cidr, e)),
+                    if let Err(e) = parse_cidr(&rule, cidr) {
+                        errors.push(e);
                     }
                 }
             }
 
+            if item.sample_rate == Some(0) {
+                errors.push(ConfigError::SampleRate { rule: rule.clone() });
+            }
+
+            // check if the filter expression validate
+            if let Some(expr) = item.match_expr.as_ref() {
+                if let Err(error) = expr.parse::<Expr>() {
+                    errors.push(ConfigError::MatchExpr {
+                        rule: rule.clone(),
+                        error,
+                    });
+                }
+            }
+
             // checks if the rule instance matches constLabel
             // several situations:
             // + Exists in constLabel but not in constValues, in this case we set values ​​to unset
@@ -102,11 +403,10 @@ impl Traffic {
                     if (!labels_map.is_empty() && !labels_map.contains(k))
                         || (labels_map.is_empty() && !values_map.is_empty())
                     {
-                        return Err(anyhow!(
-                            "label={} in the '{}' rule does not match that in constLabels or constLabels is empty",
-                            k,
-                            item.name
-                        ));
+                        errors.push(ConfigError::ConstLabel {
+                            rule: rule.clone(),
+                            label: k.to_owned(),
+                        });
                     }
                 }
             } else {
@@ -124,9 +424,84 @@ impl Traffic {
             }
             item.const_values.as_mut().unwrap().extend(replenish);
         }
-        util::lookup_interface(lookup_iface)?;
 
-        Ok(())
+        errors
+    }
+
+    /// Returns the suspicious settings of a valid configuration
+    pub fn warnings(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+
+        // the same cidr listed by several rules is matched by all of them
+        let mut lookup_cidr: HashMap<ipnetwork::Ipv4Network, &str> = HashMap::new();
+        for item in self.rules.iter().flatten() {
+            for cidr in item.cidrs.iter().flatten() {
+                let Ok(ipv4) = parse_cidr(&item.name, cidr) else {
+                    continue;
+                };
+                let Ok(network) = ipnetwork::Ipv4Network::new(ipv4.network(), ipv4.prefix()) else {
+                    continue;
+                };
+                match lookup_cidr.get(&network) {
+                    Some(rule) if *rule != item.name => {
+                        warnings.push(ConfigWarning::DuplicateCidr {
+                            rule: item.name.clone(),
+                            cidr: cidr.to_owned(),
+                            other: rule.to_string(),
+                        })
+                    }
+                    Some(_) => {}
+                    None => {
+                        lookup_cidr.insert(network, &item.name);
+                    }
+                }
+            }
+
+            let (in_iface, out_iface) = (
+                item.in_iface.as_ref().is_some_and(|i| !i.is_empty()),
+                item.out_iface.as_ref().is_some_and(|i| !i.is_empty()),
+            );
+            if !in_iface && !out_iface {
+                warnings.push(ConfigWarning::NoIface {
+                    rule: item.name.clone(),
+                });
+            }
+            for (ports, iface, field) in [
+                (&item.in_ports, in_iface, "inPorts"),
+                (&item.out_ports, out_iface, "outPorts"),
+            ] {
+                if ports.as_ref().is_some_and(|p| !p.is_empty()) && !iface {
+                    warnings.push(ConfigWarning::PortsWithoutIface {
+                        rule: item.name.clone(),
+                        field,
+                    });
+                }
+            }
+        }
+
+        let rates = self.sample_rates();
+        for item in self.rules.iter().flatten() {
+            let Some(rate) = item.sample_rate.filter(|rate| *rate > 0) else {
+                continue;
+            };
+            let netns = netns_label(item);
+            for iface in item.in_iface.iter().chain(item.out_iface.iter()).flatten() {
+                let iface_rate = rates
+                    .get(&(netns.clone(), iface.to_owned()))
+                    .copied()
+                    .unwrap_or(1);
+                if iface_rate != rate {
+                    warnings.push(ConfigWarning::SampleRateOverridden {
+                        rule: item.name.clone(),
+                        rate,
+                        iface: iface.to_owned(),
+                        iface_rate,
+                    });
+                }
+            }
+        }
+
+        warnings
     }
 
     pub fn const_labels(&self) -> Vec<String> {
@@ -135,6 +510,119 @@ impl Traffic {
             None => vec![],
         }
     }
+
+    /// Returns the sample rates by `netns` label and network interface selector,
+    /// see [crate::iface::sample_rate].
+    ///
+    /// The classifier samples the whole interface, so the rate of an interface is the lowest
+    /// rate of the rules attached to it in its namespace (a rule without `sampleRate` is not
+    /// sampled), unless it is set in `sampling.ifaces` for the namespace of netsniff,
+    /// see [ConfigWarning::SampleRateOverridden].
+    pub fn sample_rates(&self) -> SampleRates {
+        let mut rates = SampleRates::new();
+        for item in self.rules.iter().flatten() {
+            let rate = item.sample_rate.unwrap_or(1);
+            let netns = netns_label(item);
+            for iface in item.in_iface.iter().chain(item.out_iface.iter()).flatten() {
+                rates
+                    .entry((netns.clone(), iface.to_owned()))
+                    .and_modify(|r| *r = (*r).min(rate))
+                    .or_insert(rate);
+            }
+        }
+
+        // the selectors of `sampling.ifaces` take precedence over the interfaces named by the rules
+        let selectors: Vec<IfaceSet> = self
+            .sampling
+            .ifaces
+            .keys()
+            .filter_map(|selector| IfaceSet::parse([selector]).ok())
+            .collect();
+        rates.retain(|(netns, iface), _| {
+            !netns.is_empty()
+                || !is_iface_name(iface)
+                || !selectors.iter().any(|s| s.contains(iface))
+        });
+        rates.extend(
+            self.sampling
+                .ifaces
+                .iter()
+                .map(|(selector, rate)| ((String::new(), selector.to_owned()), *rate)),
+        );
+
+        // the unsampled interfaces are kept by name, so that a matching selector does not sample them
+        rates.retain(|(_, iface), rate| *rate > 1 || is_iface_name(iface));
+        rates
+    }
+
+    /// Returns the packet size histogram buckets, if the histogram is enabled
+    pub fn packet_size_buckets(&self) -> Option<Vec<f64>> {
+        self.packet_size_buckets
+            .clone()
+            .filter(|buckets| !buckets.is_empty())
+    }
+}
+
+// Returns the `netns` label of the rule, see [netns::label]
+fn netns_label(item: &ConfigItem) -> String {
+    let netns = item.netns.as_deref().and_then(|n| n.parse::<Netns>().ok());
+    netns::label(netns.as_ref()).to_owned()
+}
+
+// Whether the network interface selector is a single interface name
+fn is_iface_name(selector: &str) -> bool {
+    selector.parse::<IfaceSelector>().is_ok_and(|s| s.is_name())
+}
+
+// Parse a cidr of the rule, a single address is not a valid cidr
+fn parse_cidr(rule: &str, cidr: &str) -> Result<ipnetwork::Ipv4Network, ConfigError> {
+    let error = |reason: String| ConfigError::Cidr {
+        rule: rule.to_owned(),
+        cidr: cidr.to_owned(),
+        reason,
+    };
+    match ipnetwork::Ipv4Network::from_str(cidr) {
+        Ok(ipv4) => {
+            if ipv4.prefix() == 0x20 {
+                return Err(error(format!(
+                    "mask='{}', please provide a valid mask value",
+                    ipv4.mask()
+                )));
+            }
+            Ok(ipv4)
+        }
+        Err(e) => Err(error(e.to_string())),
+    }
+}
+
+// Read the file and substitute its `${...}` references, returns the diagnostic of the error
+fn read_source(path: &Path) -> Result<Rendered, String> {
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: error: {}", name, e))?;
+    template::render(&source, template::lookup)
+        .map_err(|e| ConfigError::Template(e).diagnostic(&name, &source.as_str().into()))
+}
+
+// Returns the files of the include entry, the rule files of a directory sorted by name
+fn include_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        fs::metadata(path)?;
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        let is_yaml = file
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml");
+        if is_yaml && file.is_file() {
+            files.push(file);
+        }
+    }
+    files.sort();
+
+    Ok(files)
 }
 
 // By default, the collector is flushed every 30 seconds.
@@ -144,25 +632,73 @@ fn default_export_interval() -> String {
 
 type OptionVec<T> = Option<Vec<T>>;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigItem {
     pub name: String,
 
     #[serde(default)]
     pub protocol: network::Proto,
 
-    pub in_ports: OptionVec<u16>,
+    #[serde(rename(deserialize = "inPorts"))]
+    pub in_ports: OptionVec<PortRange>,
+
+    #[serde(rename(deserialize = "outPorts"))]
+    pub out_ports: OptionVec<PortRange>,
+
+    #[serde(rename(deserialize = "srcPorts"))]
+    pub src_ports: OptionVec<PortRange>,
+
+    #[serde(rename(deserialize = "dstPorts"))]
+    pub dst_ports: OptionVec<PortRange>,
 
     pub cidrs: OptionVec<String>,
 
+    #[serde(rename(deserialize = "srcCidrs"))]
+    pub src_cidrs: OptionVec<String>,
+
+    #[serde(rename(deserialize = "dstCidrs"))]
+    pub dst_cidrs: OptionVec<String>,
+
+    #[serde(rename(deserialize = "excludeCidrs"))]
+    pub exclude_cidrs: OptionVec<String>,
+
+    #[serde(rename(deserialize = "excludePorts"))]
+    pub exclude_ports: OptionVec<PortRange>,
+
+    #[serde(rename(deserialize = "excludeIface"))]
+    pub exclude_iface: OptionVec<String>,
+
     #[serde(rename(deserialize = "inIface"))]
     pub in_iface: OptionVec<String>,
 
     #[serde(rename(deserialize = "outIface"))]
     pub out_iface: OptionVec<String>,
 
+    /// the network namespace of the interfaces, see [Netns]
+    pub netns: Option<String>,
+
     #[serde(rename(deserialize = "constValues"))]
     pub const_values: Option<HashMap<String, String>>,
+
+    #[serde(rename(deserialize = "tlsPorts"))]
+    pub tls_ports: OptionVec<u16>,
+
+    #[serde(rename(deserialize = "sniLabel"), default)]
+    pub sni_label: bool,
+
+    #[serde(rename(deserialize = "httpPorts"))]
+    pub http_ports: OptionVec<u16>,
+
+    #[serde(rename(deserialize = "match"))]
+    pub match_expr: Option<String>,
+
+    #[serde(rename(deserialize = "sampleRate"))]
+    pub sample_rate: Option<u32>,
+
+    /// the file the rule was read from
+    #[serde(skip)]
+    pub source: Option<String>,
 }
 
 impl ConfigItem {
@@ -181,9 +717,13 @@ impl ConfigItem {
 
 #[cfg(test)]
 mod test {
-    use std::io::Cursor;
+    use std::{fs, io::Cursor};
 
     use super::Traffic;
+    use crate::{
+        diagnostic::{ConfigError, ConfigWarning, Location},
+        iface, template,
+    };
 
     #[test]
     fn test_load_config() {
@@ -202,15 +742,332 @@ rules:
       name: l3
       age: l2
     # check
-    in_ports:
+    inPorts:
       - 8080
       - 7070
-    in_iface: [lo]
-    out_iface: [lo]
+    inIface: [lo]
+    outIface: [lo]
 "#;
 
         let reader = Cursor::new(config_str);
         let result = Traffic::load_config(reader);
         assert!(result.is_ok())
     }
+
+    #[test]
+    fn test_load_src_dst_cidrs_config() {
+        let config_str = r#"
+rules:
+  - name: cross-zone
+    srcCidrs: ["10.1.0.0/16"]
+    dstCidrs: ["10.2.0.0/16"]
+"#;
+        let traffic = Traffic::load_config(Cursor::new(config_str)).unwrap();
+        let item = &traffic.rules.unwrap()[0];
+        assert_eq!(
+            item.src_cidrs.as_deref(),
+            Some(&["10.1.0.0/16".to_string()][..])
+        );
+        assert!(item.cidrs.is_none());
+
+        let config_str = r#"
+rules:
+  - name: cross-zone
+    dstCidrs: ["10.2.0.0"]
+"#;
+        assert!(Traffic::load_config(Cursor::new(config_str)).is_err());
+    }
+
+    #[test]
+    fn test_load_match_config() {
+        let config_str = r#"
+rules:
+  - name: https
+    match: "tcp and dst port 443 and not net 10.0.0.0/8"
+"#;
+        assert!(Traffic::load_config(Cursor::new(config_str)).is_ok());
+
+        let config_str = r#"
+rules:
+  - name: https
+    match: "tcp and dst prot 443"
+"#;
+        let err = Traffic::load_config(Cursor::new(config_str)).unwrap_err();
+        assert!(err.to_string().contains("'https' rule"));
+    }
+
+    #[test]
+    fn test_sample_rates_config() {
+        let config_str = r#"
+sampling:
+  ifaces:
+    lo: 10
+rules:
+  - name: sampled
+    sampleRate: 100
+    inIface: [lo]
+  - name: unsampled
+    inIface: [lo]
+"#;
+        let mut traffic = Traffic::load_config(Cursor::new(config_str)).unwrap();
+        assert_eq!(iface::sample_rate(&traffic.sample_rates(), "", "lo"), 10);
+
+        // without the iface rate, the unsampled rule wins
+        traffic.sampling.ifaces.clear();
+        assert_eq!(
+            traffic
+                .sample_rates()
+                .get(&(String::new(), "lo".to_string())),
+            Some(&1)
+        );
+
+        // a rule naming the interface is not sampled by the pattern of another rule
+        let config_str = r#"
+rules:
+  - name: pods
+    sampleRate: 10
+    inIface: ["veth*"]
+  - name: veth0
+    inIface: [veth0]
+"#;
+        let mut traffic = Traffic::parse(config_str, false).unwrap();
+        let rates = traffic.sample_rates();
+        assert_eq!(iface::sample_rate(&rates, "", "veth0"), 1);
+        assert_eq!(iface::sample_rate(&rates, "", "veth1"), 10);
+
+        // unless the interface is sampled by `sampling.ifaces`
+        traffic.sampling.ifaces.insert("/^veth0$/".to_string(), 5);
+        let rates = traffic.sample_rates();
+        assert_eq!(iface::sample_rate(&rates, "", "veth0"), 5);
+        assert_eq!(iface::sample_rate(&rates, "", "veth1"), 10);
+
+        // the rates of the rules and of `sampling.ifaces` apply per namespace
+        let config_str = r#"
+sampling:
+  ifaces:
+    eth0: 100
+rules:
+  - name: host
+    sampleRate: 10
+    inIface: [lo]
+  - name: web
+    netns: web
+    inIface: [eth0, lo]
+"#;
+        let traffic = Traffic::parse(config_str, false).unwrap();
+        let rates = traffic.sample_rates();
+        assert_eq!(iface::sample_rate(&rates, "", "eth0"), 100);
+        assert_eq!(iface::sample_rate(&rates, "", "lo"), 10);
+        assert_eq!(iface::sample_rate(&rates, "web", "eth0"), 1);
+        assert_eq!(iface::sample_rate(&rates, "web", "lo"), 1);
+        assert!(traffic.warnings().is_empty());
+
+        let config_str = r#"
+rules:
+  - name: sampled
+    sampleRate: 0
+"#;
+        assert!(Traffic::load_config(Cursor::new(config_str)).is_err());
+    }
+
+    #[test]
+    fn test_load_exclude_config() {
+        let config_str = r#"
+rules:
+  - name: internal
+    cidrs: ["10.0.0.0/8"]
+    excludeCidrs: ["10.9.0.0/16"]
+    excludePorts: [ssh]
+    excludeIface: [lo]
+"#;
+        let traffic = Traffic::load_config(Cursor::new(config_str)).unwrap();
+        let item = &traffic.rules.unwrap()[0];
+        assert_eq!(item.exclude_ports.as_ref().unwrap()[0].to_string(), "22");
+
+        let config_str = r#"
+rules:
+  - name: internal
+    excludeCidrs: ["10.9.0.1/32"]
+"#;
+        assert!(Traffic::load_config(Cursor::new(config_str)).is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config_str = r#"
+packetSizeBuckets: [128, 64]
+rules:
+  - name: web
+    cidrs: ["10.0.0.1/32"]
+    inIface: [not-exist0]
+  - {name: "dns", match: "udp and dst prot 53", sampleRate: 0}
+"#;
+        let mut traffic: Traffic = serde_yaml::from_str(config_str).unwrap();
+        let errors = traffic.validate(true);
+        assert_eq!(errors.len(), 5);
+        assert!(matches!(errors[0], ConfigError::PacketSizeBuckets(_)));
+        assert!(matches!(
+            &errors[1],
+            ConfigError::IfaceNotFound { rule, iface } if rule == "web" && iface == "not-exist0"
+        ));
+        assert_eq!(
+            errors[1].location(config_str),
+            Some(Location {
+                line: 6,
+                column: 15
+            })
+        );
+        assert!(matches!(&errors[4], ConfigError::MatchExpr { rule, .. } if rule == "dns"));
+        assert_eq!(
+            errors[4].diagnostic("sniff.yaml", &config_str.into()),
+            format!("sniff.yaml:7:19: error: {}", errors[4])
+        );
+
+        // the interfaces of configurations for other hosts are not checked
+        assert_eq!(traffic.validate(false).len(), 4);
+
+        // the locations in a rendered source are reported in the source
+        let source = "rules:\n  - {name: web, inIface: [${NETSNIFF_TEST_UNSET:-eth0}], cidrs: [10.0.0.1/32]}\n";
+        let rendered = template::render(source, |_| None).unwrap();
+        let mut traffic: Traffic = serde_yaml::from_str(&rendered.text).unwrap();
+        let errors = traffic.validate(false);
+        assert!(matches!(errors[0], ConfigError::Cidr { .. }));
+        assert_eq!(
+            errors[0].diagnostic("sniff.yaml", &rendered),
+            format!("sniff.yaml:2:66: error: {}", errors[0])
+        );
+
+        // the interfaces matched by a pattern are not required to exist
+        let config_str = r#"
+rules:
+  - name: pods
+    inIface: ["veth*", "!veth0"]
+    outIface: ["/^veth[/"]
+"#;
+        let mut traffic: Traffic = serde_yaml::from_str(config_str).unwrap();
+        let errors = traffic.validate(true);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            &errors[0],
+            ConfigError::IfacePattern { rule, pattern, .. }
+                if rule.as_deref() == Some("pods") && pattern == "/^veth[/"
+        ));
+        assert_eq!(errors[0].location(config_str).map(|l| l.line), Some(5));
+
+        let config_str = r#"
+rules:
+  - {name: web, netns: "../web", inIface: [eth0]}
+  - {name: pod, netns: "not-exist-ns", inIface: [eth0]}
+"#;
+        let mut traffic: Traffic = serde_yaml::from_str(config_str).unwrap();
+        let errors = traffic.validate(true);
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], ConfigError::Netns { rule, .. } if rule == "web"));
+        assert!(matches!(&errors[1], ConfigError::Netns { rule, .. } if rule == "pod"));
+        assert_eq!(traffic.validate(false).len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_field_config() {
+        let config_str = r#"
+rules:
+  - name: web
+    in_ports: [80]
+"#;
+        let errors = Traffic::parse(config_str, false).unwrap_err();
+        assert!(matches!(errors[0], ConfigError::Syntax(_)));
+        assert!(errors[0].to_string().contains("unknown field `in_ports`"));
+        assert_eq!(errors[0].location(config_str).map(|l| l.line), Some(4));
+    }
+
+    #[test]
+    fn test_duplicate_rule_config() {
+        let config_str = r#"
+rules:
+  - name: web
+    inIface: [lo]
+  - name: web
+    outIface: [lo]
+  - {name: web, inIface: [lo]}
+"#;
+        let errors = Traffic::parse(config_str, false).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], ConfigError::DuplicateRule { rule, .. } if rule == "web"));
+
+        // the duplicates are located, not the first rule
+        let lines: Vec<usize> = errors
+            .iter()
+            .filter_map(|e| e.location(config_str))
+            .map(|l| l.line)
+            .collect();
+        assert_eq!(lines, vec![5, 7]);
+    }
+
+    #[test]
+    fn test_config_warnings() {
+        let config_str = r#"
+rules:
+  - name: web
+    cidrs: ["10.0.0.0/8"]
+    inIface: [lo]
+  - name: internal
+    cidrs: ["10.0.0.0/8"]
+    inPorts: [80]
+    outIface: [lo]
+"#;
+        let traffic = Traffic::parse(config_str, false).unwrap();
+        let warnings = traffic.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(matches!(
+            &warnings[0],
+            ConfigWarning::DuplicateCidr { rule, other, .. } if rule == "internal" && other == "web"
+        ));
+        assert_eq!(warnings[0].location(config_str).map(|l| l.line), Some(7));
+        assert!(matches!(
+            warnings[1],
+            ConfigWarning::PortsWithoutIface {
+                field: "inPorts",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_read_include_config() {
+        let dir = std::env::temp_dir().join(format!("netsniff-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("rules.d")).unwrap();
+        fs::write(
+            dir.join("sniff.yaml"),
+            "include: [team.yaml, rules.d]\nrules:\n  - name: main\n",
+        )
+        .unwrap();
+        fs::write(dir.join("team.yaml"), "rules:\n  - name: team\n").unwrap();
+        fs::write(dir.join("rules.d/b.yml"), "rules:\n  - name: b\n").unwrap();
+        fs::write(dir.join("rules.d/a.yaml"), "# no rules yet\n").unwrap();
+        fs::write(dir.join("rules.d/c.txt"), "not a rule file").unwrap();
+
+        let report = Traffic::read(&dir.join("sniff.yaml"), false);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let traffic = report.traffic.unwrap();
+        let rules: Vec<(&str, &str)> = traffic
+            .rules
+            .iter()
+            .flatten()
+            .map(|item| (item.name.as_str(), item.source.as_deref().unwrap()))
+            .collect();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[1].0, "team");
+        assert!(rules[1].1.ends_with("team.yaml"));
+        assert!(rules[2].1.ends_with("b.yml"));
+        assert_eq!(traffic.files.len(), 5);
+
+        // rule names are unique across the files
+        fs::write(dir.join("rules.d/b.yml"), "rules:\n  - name: team\n").unwrap();
+        let report = Traffic::read(&dir.join("sniff.yaml"), false);
+        assert!(report.traffic.is_none());
+        assert!(report.errors[0].contains("b.yml:2:5: error: rule name 'team' is not unique"));
+        assert!(report.errors[0].contains("team.yaml"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,389 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    expr::ParseError,
+    template::{Rendered, TemplateError},
+};
+
+/// An error making the configuration invalid
+#[derive(Debug)]
+pub enum ConfigError {
+    /// the configuration is not valid YAML, or has an unknown or mistyped field
+    Syntax(serde_yaml::Error),
+    /// a `${...}` reference can not be substituted
+    Template(TemplateError),
+    /// the included file or directory can not be read
+    Include {
+        path: String,
+        reason: String,
+    },
+    /// the rule name of the `source` file is already used by a rule of the `first` file
+    DuplicateRule {
+        rule: String,
+        source: Option<String>,
+        first: Option<String>,
+        /// the number of earlier rules with the name in the `source` file
+        nth: usize,
+    },
+    ExportInterval(String),
+    PacketSizeBuckets(Vec<f64>),
+    IfaceSampleRate {
+        iface: String,
+    },
+    IfaceNotFound {
+        rule: String,
+        iface: String,
+    },
+    Netns {
+        rule: String,
+        netns: String,
+        reason: String,
+    },
+    /// the network interface selector of a rule, or of the sampling rates, is invalid
+    IfacePattern {
+        rule: Option<String>,
+        pattern: String,
+        reason: String,
+    },
+    Cidr {
+        rule: String,
+        cidr: String,
+        reason: String,
+    },
+    SampleRate {
+        rule: String,
+    },
+    MatchExpr {
+        rule: String,
+        error: ParseError,
+    },
+    ConstLabel {
+        rule: String,
+        label: String,
+    },
+}
+
+impl ConfigError {
+    /// Returns the name of the rule the error was found in, if any
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            ConfigError::DuplicateRule { rule, .. }
+            | ConfigError::IfaceNotFound { rule, .. }
+            | ConfigError::Netns { rule, .. }
+            | ConfigError::Cidr { rule, .. }
+            | ConfigError::SampleRate { rule }
+            | ConfigError::MatchExpr { rule, .. }
+            | ConfigError::ConstLabel { rule, .. } => Some(rule),
+            ConfigError::IfacePattern { rule, .. } => rule.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the location of the error in the configuration source
+    pub fn location(&self, source: &str) -> Option<Location> {
+        let needle = match self {
+            ConfigError::Syntax(e) => {
+                return e.location().map(|l| Location {
+                    line: l.line(),
+                    column: l.column(),
+                })
+            }
+            ConfigError::Template(e) => return Some(e.location),
+            ConfigError::Include { path, .. } => path,
+            ConfigError::DuplicateRule { rule, nth, .. } => {
+                return locate_nth(source, rule, *nth, "name")
+            }
+            ConfigError::ExportInterval(_) => "exportInterval",
+            ConfigError::PacketSizeBuckets(_) => "packetSizeBuckets",
+            ConfigError::IfaceSampleRate { iface } => {
+                return locate(source, None, "sampling").and_then(|sampling| {
+                    locate_from(source, sampling.line - 1, None, iface).or(Some(sampling))
+                })
+            }
+            ConfigError::IfaceNotFound { iface, .. } => iface,
+            ConfigError::Netns { netns, .. } => netns,
+            ConfigError::IfacePattern { pattern, .. } => pattern,
+            ConfigError::Cidr { cidr, .. } => cidr,
+            ConfigError::SampleRate { .. } => "sampleRate",
+            ConfigError::MatchExpr { .. } => "match",
+            ConfigError::ConstLabel { label, .. } => label,
+        };
+
+        locate(source, self.rule(), needle)
+    }
+
+    /// Formats the error as `<path>:<line>:<column>: error: <message>`,
+    /// the location is searched in the rendered text and reported in its source
+    pub fn diagnostic(&self, path: &str, source: &Rendered) -> String {
+        let location = match self {
+            // the references are located in the source already
+            ConfigError::Template(e) => Some(e.location),
+            _ => self
+                .location(&source.text)
+                .map(|l| source.source_location(l)),
+        };
+        diagnostic(path, location, "error", self)
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Syntax(e) => write!(f, "{}", e),
+            ConfigError::Template(e) => write!(f, "{}", e.message),
+            ConfigError::Include { path, reason } => {
+                write!(f, "failed to include '{}' by {}", path, reason)
+            }
+            ConfigError::DuplicateRule { rule, first, .. } => match first {
+                Some(first) => write!(
+                    f,
+                    "rule name '{}' is not unique, it is also defined in '{}'",
+                    rule, first
+                ),
+                None => write!(f, "rule name '{}' is not unique", rule),
+            },
+            ConfigError::ExportInterval(interval) => {
+                write!(f, "exportInterval='{}' is not a valid duration", interval)
+            }
+            ConfigError::PacketSizeBuckets(buckets) => write!(
+                f,
+                "packetSizeBuckets={:?} must be positive and in increasing order",
+                buckets
+            ),
+            ConfigError::IfaceSampleRate { iface } => {
+                write!(f, "sample rate of the '{}' iface must be at least 1", iface)
+            }
+            ConfigError::IfaceNotFound { rule, iface } => write!(
+                f,
+                "'{}' network interface in the '{}' rule not exist in current machine",
+                iface, rule
+            ),
+            ConfigError::Netns {
+                rule,
+                netns,
+                reason,
+            } => write!(
+                f,
+                "netns='{}' in the '{}' rule is invalid: {}",
+                netns, rule, reason
+            ),
+            ConfigError::IfacePattern { rule, reason, .. } => match rule {
+                Some(rule) => write!(f, "{} in the '{}' rule", reason, rule),
+                None => write!(f, "{} in sampling.ifaces", reason),
+            },
+            ConfigError::Cidr { rule, cidr, reason } => write!(
+                f,
+                "cidr='{}' in the '{}' rule is invalid: {}",
+                cidr, rule, reason
+            ),
+            ConfigError::SampleRate { rule } => {
+                write!(f, "sampleRate in the '{}' rule must be at least 1", rule)
+            }
+            ConfigError::MatchExpr { rule, error } => write!(
+                f,
+                "failed to parse match expression in the '{}' rule by {}",
+                rule, error
+            ),
+            ConfigError::ConstLabel { rule, label } => write!(
+                f,
+                "label={} in the '{}' rule does not match that in constLabels or constLabels is empty",
+                label, rule
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A suspicious but valid configuration
+#[derive(Debug)]
+pub enum ConfigWarning {
+    /// the cidr is also listed by another rule, both rules are matched
+    DuplicateCidr {
+        rule: String,
+        cidr: String,
+        other: String,
+    },
+    /// the rule has no network interface, it never matches in server mode
+    NoIface { rule: String },
+    /// ports of a traffic direction without a network interface for it
+    PortsWithoutIface { rule: String, field: &'static str },
+    SampleRateOverridden {
+        rule: String,
+        rate: u32,
+        iface: String,
+        iface_rate: u32,
+    },
+}
+
+impl ConfigWarning {
+    pub fn rule(&self) -> &str {
+        match self {
+            ConfigWarning::DuplicateCidr { rule, .. }
+            | ConfigWarning::NoIface { rule }
+            | ConfigWarning::PortsWithoutIface { rule, .. }
+            | ConfigWarning::SampleRateOverridden { rule, .. } => rule,
+        }
+    }
+
+    /// Returns the location of the warning in the configuration source
+    pub fn location(&self, source: &str) -> Option<Location> {
+        let needle = match self {
+            ConfigWarning::DuplicateCidr { cidr, .. } => cidr,
+            ConfigWarning::NoIface { .. } => "name",
+            ConfigWarning::PortsWithoutIface { field, .. } => field,
+            ConfigWarning::SampleRateOverridden { .. } => "sampleRate",
+        };
+
+        locate(source, Some(self.rule()), needle)
+    }
+
+    /// Formats the warning as `<path>:<line>:<column>: warning: <message>`
+    pub fn diagnostic(&self, path: &str, source: &Rendered) -> String {
+        let location = self
+            .location(&source.text)
+            .map(|l| source.source_location(l));
+        diagnostic(path, location, "warning", self)
+    }
+}
+
+impl Display for ConfigWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigWarning::DuplicateCidr { rule, cidr, other } => write!(
+                f,
+                "cidr='{}' in the '{}' rule is also listed by the '{}' rule",
+                cidr, rule, other
+            ),
+            ConfigWarning::NoIface { rule } => write!(
+                f,
+                "the '{}' rule has neither inIface nor outIface, it never matches",
+                rule
+            ),
+            ConfigWarning::PortsWithoutIface { rule, field } => write!(
+                f,
+                "{} in the '{}' rule is ignored without {}",
+                field,
+                rule,
+                if *field == "inPorts" {
+                    "inIface"
+                } else {
+                    "outIface"
+                }
+            ),
+            ConfigWarning::SampleRateOverridden {
+                rule,
+                rate,
+                iface,
+                iface_rate,
+            } => write!(
+                f,
+                "sampleRate={} of the '{}' rule is overridden by rate={} of the '{}' iface",
+                rate, rule, iface_rate, iface
+            ),
+        }
+    }
+}
+
+/// A position in the configuration source, starting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+fn diagnostic(path: &str, location: Option<Location>, level: &str, msg: &dyn Display) -> String {
+    match location {
+        Some(Location { line, column }) => {
+            format!("{}:{}:{}: {}: {}", path, line, column, level, msg)
+        }
+        None => format!("{}: {}: {}", path, level, msg),
+    }
+}
+
+/// Returns the location of the first `needle` token in the rule, or in the
+/// whole source without a rule. Falls back to the name of the rule.
+pub fn locate(source: &str, rule: Option<&str>, needle: &str) -> Option<Location> {
+    match rule {
+        Some(rule) => locate_nth(source, rule, 0, needle),
+        None => locate_from(source, 0, None, needle),
+    }
+}
+
+/// Same as [locate] in the `nth` rule with the name, counting from 0
+fn locate_nth(source: &str, rule: &str, nth: usize, needle: &str) -> Option<Location> {
+    let start = source
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| is_rule_start(line, rule))
+        .nth(nth)?
+        .0;
+    let indent = indent(source.lines().nth(start)?);
+    locate_from(source, start, Some(indent), needle).or_else(|| {
+        locate_from(source, start, Some(indent), "name").or(Some(Location {
+            line: start + 1,
+            column: indent + 1,
+        }))
+    })
+}
+
+/// Search the needle from the `start` line, until the next list item at `indent` if any
+fn locate_from(
+    source: &str,
+    start: usize,
+    indent: Option<usize>,
+    needle: &str,
+) -> Option<Location> {
+    for (n, line) in source.lines().enumerate().skip(start) {
+        if let Some(indent) = indent {
+            let item = line.trim_start();
+            if n > start
+                && !item.is_empty()
+                && !item.starts_with('#')
+                && self::indent(line) <= indent
+            {
+                break;
+            }
+        }
+
+        // skip the comments
+        let code = line.split(" #").next().unwrap_or(line);
+        if code.trim_start().starts_with('#') {
+            continue;
+        }
+        if let Some(column) = find_token(code, needle) {
+            return Some(Location {
+                line: n + 1,
+                column: column + 1,
+            });
+        }
+    }
+
+    None
+}
+
+/// Returns the byte offset of the needle, not being part of a longer word
+fn find_token(line: &str, needle: &str) -> Option<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || "_-./".contains(c);
+    line.match_indices(needle).map(|(i, _)| i).find(|&i| {
+        !line[..i].chars().next_back().is_some_and(is_word)
+            && !line[i + needle.len()..].chars().next().is_some_and(is_word)
+    })
+}
+
+/// Whether the line starts the rule, as a block (`- name: web`)
+/// or a flow (`- {name: web, ...}`) mapping
+fn is_rule_start(line: &str, rule: &str) -> bool {
+    let line = line.trim().trim_start_matches('-').trim_start();
+    line.trim_start_matches('{').split(',').any(|field| {
+        field
+            .trim()
+            .strip_prefix("name:")
+            .map(|name| name.trim_matches([' ', '"', '\'', '}']) == rule)
+            .unwrap_or(false)
+    })
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
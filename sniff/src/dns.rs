@@ -0,0 +1,362 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use network_types::ip::IpProto;
+
+use crate::network::Packet;
+
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+const MAX_LABEL_JUMPS: usize = 16;
+const MAX_QUESTIONS: u16 = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// A DNS message decoded from the leading payload bytes of a packet.
+///
+/// The payload may be truncated, so the answer section only holds the
+/// addresses that could be fully decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub response: bool,
+    pub rcode: u8,
+    pub questions: Vec<Question>,
+    pub answers: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+}
+
+/// Decode the DNS message carried by the packet, if any.
+///
+/// Only packets from or to port 53 are considered, DNS over TCP
+/// additionally carries a two bytes length prefix.
+pub fn decode(pkt: &Packet) -> Option<DnsMessage> {
+    if pkt.source != DNS_PORT && pkt.dst != DNS_PORT {
+        return None;
+    }
+
+    match pkt.proto {
+        IpProto::Udp => DnsMessage::parse(&pkt.payload),
+        IpProto::Tcp if pkt.payload.len() > 2 => DnsMessage::parse(&pkt.payload[2..]),
+        _ => None,
+    }
+}
+
+impl DnsMessage {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        let id = read_u16(buf, 0)?;
+        let flags = read_u16(buf, 2)?;
+        let qd_count = read_u16(buf, 4)?;
+        let an_count = read_u16(buf, 6)?;
+
+        // only standard queries are decoded
+        if (flags >> 11) & 0xf != 0 || qd_count == 0 {
+            return None;
+        }
+
+        let mut offset = HEADER_LEN;
+        // the count comes from the packet, a malformed one must not make us allocate much
+        let mut questions = Vec::with_capacity(qd_count.min(MAX_QUESTIONS) as usize);
+        for _ in 0..qd_count {
+            let (name, next) = read_name(buf, offset)?;
+            let qtype = read_u16(buf, next)?;
+            questions.push(Question { name, qtype });
+            offset = next + 4;
+        }
+
+        let mut answers = Vec::new();
+        for _ in 0..an_count {
+            let Some((_, next)) = read_name(buf, offset) else {
+                break;
+            };
+            let (Some(rtype), Some(rd_len)) = (read_u16(buf, next), read_u16(buf, next + 8)) else {
+                break;
+            };
+            let rdata = next + 10;
+            let Some(data) = buf.get(rdata..rdata + rd_len as usize) else {
+                break;
+            };
+            match (rtype, data.len()) {
+                (TYPE_A, 4) => answers.push(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                ))),
+                (TYPE_AAAA, 16) => {
+                    let octets: [u8; 16] = data.try_into().ok()?;
+                    answers.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+                _ => {}
+            }
+            offset = rdata + rd_len as usize;
+        }
+
+        Some(Self {
+            id,
+            response: flags & 0x8000 != 0,
+            rcode: (flags & 0xf) as u8,
+            questions,
+            answers,
+        })
+    }
+
+    pub fn qtype(&self) -> String {
+        self.questions
+            .first()
+            .map(|q| qtype_str(q.qtype))
+            .unwrap_or_default()
+    }
+}
+
+impl Display for DnsMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self
+            .questions
+            .first()
+            .map(|q| q.name.as_str())
+            .unwrap_or_default();
+        if self.response {
+            let answers: Vec<String> = self.answers.iter().map(|a| a.to_string()).collect();
+            write!(
+                f,
+                "  dns response id={:<6}{:<9}{:<6}{} -> [{}]",
+                self.id,
+                rcode_str(self.rcode),
+                self.qtype(),
+                name,
+                answers.join(","),
+            )
+        } else {
+            write!(
+                f,
+                "  dns query    id={:<6}{:<6}{}",
+                self.id,
+                self.qtype(),
+                name
+            )
+        }
+    }
+}
+
+pub fn qtype_str(qtype: u16) -> String {
+    match qtype {
+        TYPE_A => "A".to_string(),
+        2 => "NS".to_string(),
+        5 => "CNAME".to_string(),
+        6 => "SOA".to_string(),
+        12 => "PTR".to_string(),
+        15 => "MX".to_string(),
+        16 => "TXT".to_string(),
+        TYPE_AAAA => "AAAA".to_string(),
+        33 => "SRV".to_string(),
+        65 => "HTTPS".to_string(),
+        255 => "ANY".to_string(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+pub fn rcode_str(rcode: u8) -> &'static str {
+    match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "OTHER",
+    }
+}
+
+/// Matches DNS responses to their queries in order to measure the resolution latency.
+///
+/// Queries are identified by the client address, the client port and the query id.
+#[derive(Debug, Default)]
+pub struct DnsTracker {
+    pending: Mutex<HashMap<(Ipv4Addr, u16, u16), Instant>>,
+}
+
+impl DnsTracker {
+    /// Queries that are not answered within this duration are dropped
+    const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+    const MAX_PENDING: usize = 4096;
+
+    /// Record a query or resolve a response, returning the latency of the latter.
+    pub fn track(&self, pkt: &Packet, msg: &DnsMessage) -> Option<Duration> {
+        let mut pending = self.pending.lock().unwrap();
+        let now = Instant::now();
+
+        if !msg.response {
+            // under a query flood, new queries are not tracked until the pending ones expire
+            let key = (pkt.src_ip, pkt.source, msg.id);
+            if pending.len() < Self::MAX_PENDING || pending.contains_key(&key) {
+                pending.insert(key, now);
+            }
+            return None;
+        }
+
+        pending
+            .remove(&(pkt.dst_ip, pkt.dst, msg.id))
+            .map(|at| now.duration_since(at))
+    }
+
+    /// Drop the queries that were not answered in time
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(|_, at| now.duration_since(*at) < Self::QUERY_TIMEOUT);
+    }
+
+    /// Periodically sweep the pending queries
+    pub async fn expire(&self, interval: Duration) {
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            self.sweep();
+        }
+    }
+}
+
+#[inline]
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    buf.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Read a (possibly compressed) domain name,
+/// returning the name and the offset right after it.
+fn read_name(buf: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = offset;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len {
+            0 => {
+                pos += 1;
+                break;
+            }
+            l if l & 0xc0 == 0xc0 => {
+                jumps += 1;
+                if jumps > MAX_LABEL_JUMPS {
+                    return None;
+                }
+                let ptr = (read_u16(buf, pos)? & 0x3fff) as usize;
+                end.get_or_insert(pos + 2);
+                pos = ptr;
+            }
+            l => {
+                let label = buf.get(pos + 1..pos + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                pos += 1 + l;
+            }
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{DnsMessage, DnsTracker};
+    use crate::network;
+
+    #[test]
+    fn test_parse_query() {
+        let buf = [
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, //
+            0x00, 0x01, 0x00, 0x01,
+        ];
+
+        let msg = DnsMessage::parse(&buf).unwrap();
+        assert_eq!(msg.id, 0x1234);
+        assert!(!msg.response);
+        assert_eq!(msg.questions[0].name, "example.com");
+        assert_eq!(msg.qtype(), "A");
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let buf = [
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, //
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, //
+            0x00, 0x01, 0x00, 0x01, //
+            // compressed name pointing to the question
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, 0x00, 0x04, //
+            93, 184, 216, 34,
+        ];
+
+        let msg = DnsMessage::parse(&buf).unwrap();
+        assert!(msg.response);
+        assert_eq!(msg.rcode, 0);
+        assert_eq!(
+            msg.answers,
+            vec![IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]
+        );
+
+        // a truncated answer section still yields the question
+        let msg = DnsMessage::parse(&buf[..buf.len() - 2]).unwrap();
+        assert_eq!(msg.questions[0].name, "example.com");
+        assert!(msg.answers.is_empty());
+    }
+
+    #[test]
+    fn test_tracker_capacity() {
+        let packet = |client: u16, response: bool| {
+            let (client, server) = (([10, 0, 0, 1], client), ([10, 0, 0, 53], 53));
+            let (src, dst) = if response {
+                (server, client)
+            } else {
+                (client, server)
+            };
+            network::test::packet(src, dst).build().pkt
+        };
+        let msg = |response: bool| DnsMessage {
+            id: 1,
+            response,
+            rcode: 0,
+            questions: Vec::new(),
+            answers: Vec::new(),
+        };
+
+        let tracker = DnsTracker::default();
+        for client in 0..DnsTracker::MAX_PENDING as u16 {
+            tracker.track(&packet(client, false), &msg(false));
+        }
+        // the pending queries are full, the new query is dropped
+        let client = DnsTracker::MAX_PENDING as u16;
+        tracker.track(&packet(client, false), &msg(false));
+        assert_eq!(
+            tracker.pending.lock().unwrap().len(),
+            DnsTracker::MAX_PENDING
+        );
+        assert!(tracker.track(&packet(client, true), &msg(true)).is_none());
+
+        assert!(tracker.track(&packet(0, true), &msg(true)).is_some());
+        tracker.sweep();
+        assert_eq!(
+            tracker.pending.lock().unwrap().len(),
+            DnsTracker::MAX_PENDING - 1
+        );
+    }
+}
@@ -1,8 +1,13 @@
+use std::mem;
+
 use aya::{
     include_bytes_aligned,
-    maps::RingBuf,
-    programs::{tc, SchedClassifier},
-    EbpfLoader,
+    maps::{lpm_trie::Key, Array, LpmTrie, RingBuf},
+    programs::{
+        tc::{self, SchedClassifierLink},
+        SchedClassifier,
+    },
+    Ebpf, EbpfLoader,
 };
 use libc::{self, c_int};
 use log::{error, info, warn};
@@ -12,16 +17,33 @@ use tokio::{
     sync::mpsc,
 };
 
-use crate::network::{NetworkPacket, Packet};
+use crate::{
+    netns::{self, Netns},
+    network::{NetworkPacket, Packet},
+    prefilter::Predicate,
+};
 
-pub async fn load_ingress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<NetworkPacket>) {
+pub async fn load_ingress_sched_cls(
+    iface: String,
+    netns: Option<Netns>,
+    predicate: Predicate,
+    tx: mpsc::Sender<NetworkPacket>,
+) {
     let ret = set_rlimit();
     if ret != 0 {
         error!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
     let mut ebpf = match EbpfLoader::new()
-        .set_global("SNIFF_PROTOCOL", &proto, true)
+        .set_global("SNIFF_PROTOCOL", &predicate.proto_num(), true)
+        .set_global("SNIFF_FLOW", &(Flow::Ingress as i32), true)
+        .set_global("FILTER_FLAGS", &predicate.flags(), true)
+        .set_global("SAMPLE_RATE", &predicate.sample_rate, true)
+        .set_global(
+            "SAMPLE_FLOW_HASH",
+            &(predicate.sample_flow_hash as u32),
+            true,
+        )
         .load(include_bytes_aligned!(concat!(
             env!("OUT_DIR"),
             "/",
@@ -38,11 +60,18 @@ pub async fn load_ingress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<
         }
     };
 
+    if let Err(e) = load_predicate(&mut ebpf, &predicate) {
+        error!(
+            "failed to load the ingress eBPF program(TC) prefilter by error: {}",
+            e
+        );
+        return;
+    }
+
     if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
         warn!("failed to initialize kernel eBPF logger: {}", e);
     }
 
-    let _ = tc::qdisc_add_clsact(&iface);
     let prog: &mut SchedClassifier = ebpf.program_mut("sniff").unwrap().try_into().unwrap();
     if let Err(e) = prog.load() {
         error!(
@@ -52,19 +81,25 @@ pub async fn load_ingress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<
         return;
     };
 
-    if let Err(e) = prog.attach(&iface, tc::TcAttachType::Ingress) {
-        error!(
-            "failed to attach the ingress eBPF program(TC) to the '{}' network interface by error: {}",
-            iface,
-            e
-        );
-        return;
-    } else {
-        info!(
-            "success to attach the ingress eBPF program(TC) to the '{}' network interface!",
-            iface
-        );
-    }
+    let _link = match attach(prog, &iface, tc::TcAttachType::Ingress, netns.as_ref()) {
+        Ok(link) => {
+            info!(
+                "success to attach the ingress eBPF program(TC) to the '{}' network interface{}!",
+                iface,
+                in_netns(netns.as_ref())
+            );
+            link
+        }
+        Err(e) => {
+            error!(
+                "failed to attach the ingress eBPF program(TC) to the '{}' network interface{} by error: {}",
+                iface,
+                in_netns(netns.as_ref()),
+                e
+            );
+            return;
+        }
+    };
 
     let map = match RingBuf::try_from(ebpf.map_mut("PACKET_DATA").unwrap()) {
         Ok(map) => map,
@@ -77,6 +112,7 @@ pub async fn load_ingress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<
         }
     };
 
+    let netns = netns::label(netns.as_ref()).to_owned();
     let mut fd = AsyncFd::new(map).unwrap();
     loop {
         let mut guard = fd.ready_mut(Interest::READABLE).await.unwrap();
@@ -87,8 +123,10 @@ pub async fn load_ingress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<
 
             tx.send(NetworkPacket {
                 iface: iface.to_owned(),
+                netns: netns.clone(),
                 flow: Flow::Ingress,
                 pkt: packet,
+                sample_rate: predicate.sample_rate,
             })
             .await
             .expect("failed to send packet to rx by closed channel");
@@ -97,14 +135,27 @@ pub async fn load_ingress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<
     }
 }
 
-pub async fn load_egress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<NetworkPacket>) {
+pub async fn load_egress_sched_cls(
+    iface: String,
+    netns: Option<Netns>,
+    predicate: Predicate,
+    tx: mpsc::Sender<NetworkPacket>,
+) {
     let ret = set_rlimit();
     if ret != 0 {
         error!("remove limit on locked memory failed, ret is: {}", ret);
     }
 
     let mut ebpf = match EbpfLoader::new()
-        .set_global("SNIFF_PROTOCOL", &proto, true)
+        .set_global("SNIFF_PROTOCOL", &predicate.proto_num(), true)
+        .set_global("SNIFF_FLOW", &(Flow::Egress as i32), true)
+        .set_global("FILTER_FLAGS", &predicate.flags(), true)
+        .set_global("SAMPLE_RATE", &predicate.sample_rate, true)
+        .set_global(
+            "SAMPLE_FLOW_HASH",
+            &(predicate.sample_flow_hash as u32),
+            true,
+        )
         .load(include_bytes_aligned!(concat!(
             env!("OUT_DIR"),
             "/",
@@ -121,11 +172,18 @@ pub async fn load_egress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<N
         }
     };
 
+    if let Err(e) = load_predicate(&mut ebpf, &predicate) {
+        error!(
+            "failed to load the egress eBPF program(TC) prefilter by error: {}",
+            e
+        );
+        return;
+    }
+
     if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
         warn!("failed to initialize kernel eBPF logger: {}", e);
     }
 
-    let _ = tc::qdisc_add_clsact(&iface);
     let prog: &mut SchedClassifier = ebpf.program_mut("sniff").unwrap().try_into().unwrap();
     if let Err(e) = prog.load() {
         error!(
@@ -135,18 +193,25 @@ pub async fn load_egress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<N
         return;
     };
 
-    if let Err(e) = prog.attach(&iface, tc::TcAttachType::Egress) {
-        error!(
-            "failed to attach the egress eBPF program(TC) to the '{}' network interface by error: {}",
-            iface, e
-        );
-        return;
-    } else {
-        info!(
-            "success to attach the egress eBPF program(TC) to the '{}' network interface!",
-            iface
-        );
-    }
+    let _link = match attach(prog, &iface, tc::TcAttachType::Egress, netns.as_ref()) {
+        Ok(link) => {
+            info!(
+                "success to attach the egress eBPF program(TC) to the '{}' network interface{}!",
+                iface,
+                in_netns(netns.as_ref())
+            );
+            link
+        }
+        Err(e) => {
+            error!(
+                "failed to attach the egress eBPF program(TC) to the '{}' network interface{} by error: {}",
+                iface,
+                in_netns(netns.as_ref()),
+                e
+            );
+            return;
+        }
+    };
 
     let map = match RingBuf::try_from(ebpf.map_mut("PACKET_DATA").unwrap()) {
         Ok(map) => map,
@@ -159,6 +224,7 @@ pub async fn load_egress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<N
         }
     };
 
+    let netns = netns::label(netns.as_ref()).to_owned();
     let mut fd = AsyncFd::new(map).unwrap();
     loop {
         let mut guard = fd.ready_mut(Interest::READABLE).await.unwrap();
@@ -169,8 +235,10 @@ pub async fn load_egress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<N
 
             tx.send(NetworkPacket {
                 iface: iface.to_owned(),
+                netns: netns.clone(),
                 flow: Flow::Egress,
                 pkt: packet,
+                sample_rate: predicate.sample_rate,
             })
             .await
             .expect("failed to send packet to rx by closed channel");
@@ -179,7 +247,7 @@ pub async fn load_egress_sched_cls(iface: String, proto: i32, tx: mpsc::Sender<N
     }
 }
 
-pub fn check_attach(iface: String, flow: Flow) {
+pub fn check_attach(iface: String, netns: Option<&Netns>, flow: Flow) {
     let ret = set_rlimit();
     if ret != 0 {
         error!("remove limit on locked memory failed, ret is: {}", ret);
@@ -216,7 +284,6 @@ pub fn check_attach(iface: String, flow: Flow) {
         warn!("failed to initialize kernel eBPF logger: {}", e);
     }
 
-    let _ = tc::qdisc_add_clsact(&iface);
     let prog: &mut SchedClassifier = ebpf.program_mut("sniff").unwrap().try_into().unwrap();
     if let Err(e) = prog.load() {
         error!(
@@ -226,18 +293,27 @@ pub fn check_attach(iface: String, flow: Flow) {
         return;
     };
 
-    if let Err(e) = prog.attach(&iface, attach_type) {
-        error!(
-            "failed to attach the {:?} eBPF program(TC) to the '{}' network interface by error: {}",
-            attach_type, iface, e
-        );
-        return;
-    } else {
-        info!(
-            "success to attach the {:?} eBPF program(TC) to the '{}' network interface!",
-            attach_type, iface
-        );
-    }
+    let _link = match attach(prog, &iface, attach_type, netns) {
+        Ok(link) => {
+            info!(
+                "success to attach the {:?} eBPF program(TC) to the '{}' network interface{}!",
+                attach_type,
+                iface,
+                in_netns(netns)
+            );
+            link
+        }
+        Err(e) => {
+            error!(
+                "failed to attach the {:?} eBPF program(TC) to the '{}' network interface{} by error: {}",
+                attach_type,
+                iface,
+                in_netns(netns),
+                e
+            );
+            return;
+        }
+    };
 
     match RingBuf::try_from(ebpf.map_mut("PACKET_DATA").unwrap()) {
         Ok(_) => {
@@ -255,6 +331,71 @@ pub fn check_attach(iface: String, flow: Flow) {
     }
 }
 
+/// A program attached to a network interface, detached when dropped.
+///
+/// The program is detached by the index of the interface, which is only
+/// valid in the network namespace of the interface.
+struct NetnsLink {
+    link: Option<SchedClassifierLink>,
+    netns: Option<Netns>,
+}
+
+impl Drop for NetnsLink {
+    fn drop(&mut self) {
+        let mut link = self.link.take();
+        let Some(netns) = &self.netns else {
+            return;
+        };
+        // the programs are gone with the interfaces of a removed namespace
+        if !netns.exists() || netns.enter(|| drop(link.take())).is_err() {
+            mem::forget(link);
+        }
+    }
+}
+
+/// Attach the program to the network interface of the namespace
+fn attach(
+    prog: &mut SchedClassifier,
+    iface: &str,
+    attach_type: tc::TcAttachType,
+    netns: Option<&Netns>,
+) -> anyhow::Result<NetnsLink> {
+    let link_id = netns::enter(netns, || {
+        let _ = tc::qdisc_add_clsact(iface);
+        prog.attach(iface, attach_type)
+    })??;
+
+    Ok(NetnsLink {
+        link: Some(prog.take_link(link_id)?),
+        netns: netns.cloned(),
+    })
+}
+
+fn in_netns(netns: Option<&Netns>) -> String {
+    netns
+        .map(|netns| format!(" in the '{}' netns", netns))
+        .unwrap_or_default()
+}
+
+/// Populate the maps of the in-kernel prefilter, only the checks enabled by `FILTER_FLAGS` read them
+fn load_predicate(ebpf: &mut Ebpf, predicate: &Predicate) -> anyhow::Result<()> {
+    let mut cidrs: LpmTrie<_, u32, u8> = LpmTrie::try_from(ebpf.map_mut("FILTER_CIDRS").unwrap())?;
+    for (prefix, addr) in predicate.cidr_keys() {
+        cidrs.insert(&Key::new(prefix, addr), 1, 0)?;
+    }
+
+    if let Some(bitmap) = predicate.port_bitmap() {
+        let mut ports: Array<_, u64> = Array::try_from(ebpf.map_mut("FILTER_PORTS").unwrap())?;
+        for (index, bits) in bitmap.into_iter().enumerate() {
+            if bits != 0 {
+                ports.set(index as u32, bits, 0)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[inline]
 fn set_rlimit() -> c_int {
     let rlim = libc::rlimit {
@@ -0,0 +1,452 @@
+use std::{fmt::Display, net::Ipv4Addr, str::FromStr};
+
+use ipnetwork::Ipv4Network;
+use network_types::ip::IpProto;
+use sniff_common::Flow;
+
+use crate::{
+    network::{NetworkPacket, Proto},
+    port::PortRange,
+};
+
+/// Which side of the packet a primitive applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    Src,
+    Dst,
+    /// either the source or the destination
+    Any,
+}
+
+/// The matcher tree of a filter expression.
+///
+/// The syntax is a subset of the tcpdump one, e.g. `tcp and dst port 443 and not net 10.0.0.0/8`:
+///
+/// * `tcp`, `udp`, `ip`
+/// * `[src|dst] host <addr>`, `[src|dst] net <cidr>`
+/// * `[tcp|udp] [src|dst] port <port>`, `[tcp|udp] [src|dst] portrange <start-end>`
+/// * `inbound`, `outbound`, `less <len>`, `greater <len>`
+/// * `and`/`&&`, `or`/`||`, `not`/`!` and parentheses, `and` and `or` have the same
+///   precedence and are evaluated from left to right as in tcpdump
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// every packet, we only capture IPv4 traffic
+    Ip,
+    Proto(Proto),
+    Host(Dir, Ipv4Addr),
+    Net(Dir, Ipv4Network),
+    Port(Dir, PortRange),
+    Traffic(Flow),
+    Less(u16),
+    Greater(u16),
+}
+
+impl Expr {
+    pub fn matches(&self, pkt: &NetworkPacket) -> bool {
+        let p = &pkt.pkt;
+        match self {
+            Expr::And(l, r) => l.matches(pkt) && r.matches(pkt),
+            Expr::Or(l, r) => l.matches(pkt) || r.matches(pkt),
+            Expr::Not(e) => !e.matches(pkt),
+            Expr::Ip => true,
+            Expr::Proto(proto) => match proto {
+                Proto::TCP => matches!(p.proto, IpProto::Tcp),
+                Proto::UDP => matches!(p.proto, IpProto::Udp),
+                Proto::ALL => true,
+            },
+            Expr::Host(dir, addr) => dir.test(|src| *addr == if src { p.src_ip } else { p.dst_ip }),
+            Expr::Net(dir, net) => {
+                dir.test(|src| net.contains(if src { p.src_ip } else { p.dst_ip }))
+            }
+            Expr::Port(dir, ports) => {
+                dir.test(|src| ports.contains(if src { p.source } else { p.dst }))
+            }
+            Expr::Traffic(flow) => matches!(
+                (flow, &pkt.flow),
+                (Flow::Ingress, Flow::Ingress) | (Flow::Egress, Flow::Egress)
+            ),
+            Expr::Less(len) => p.length <= *len,
+            Expr::Greater(len) => p.length >= *len,
+        }
+    }
+
+    /// Returns the primitives the expression requires, i.e. the operands of its top-level `and`s
+    pub fn conjuncts(&self) -> Vec<&Expr> {
+        match self {
+            Expr::And(l, r) => {
+                let mut conjuncts = l.conjuncts();
+                conjuncts.extend(r.conjuncts());
+                conjuncts
+            }
+            _ => vec![self],
+        }
+    }
+
+    fn and(l: Expr, r: Expr) -> Expr {
+        Expr::And(Box::new(l), Box::new(r))
+    }
+}
+
+impl Dir {
+    // `f` is given whether the source side is tested
+    fn test<F: Fn(bool) -> bool>(&self, f: F) -> bool {
+        match self {
+            Dir::Src => f(true),
+            Dir::Dst => f(false),
+            Dir::Any => f(true) || f(false),
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            src: s,
+            tokens: tokenize(s),
+            cur: 0,
+        };
+        let expr = parser.parse_expr()?;
+        match parser.peek() {
+            Some(tok) => Err(parser.error(tok.pos, format!("unexpected '{}'", tok.text))),
+            None => Ok(expr),
+        }
+    }
+}
+
+/// A syntax error in a filter expression, pointing at the offending token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub expr: String,
+    /// byte offset of the offending token, the length of the expression at its end
+    pub pos: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let column = self.expr[..self.pos].chars().count();
+        writeln!(
+            f,
+            "{} at column {} of the filter expression",
+            self.message,
+            column + 1
+        )?;
+        writeln!(f, "  {}", self.expr)?;
+        write!(f, "  {}^", " ".repeat(column))
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+/// Split the expression into words, parentheses, `&&`, `||` and a leading `!` are tokens
+/// on their own, e.g. `tcp&&!udp`
+fn tokenize(s: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let len = match c {
+            '(' | ')' => 1,
+            '!' if start.is_none() => 1,
+            '&' | '|' if chars.peek().is_some_and(|(_, next)| *next == c) => 2,
+            _ => 0,
+        };
+        if c.is_whitespace() || len > 0 {
+            if let Some(begin) = start.take() {
+                tokens.push(Token {
+                    text: &s[begin..i],
+                    pos: begin,
+                });
+            }
+            if len > 0 {
+                tokens.push(Token {
+                    text: &s[i..i + len],
+                    pos: i,
+                });
+            }
+            if len == 2 {
+                chars.next();
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(begin) = start {
+        tokens.push(Token {
+            text: &s[begin..],
+            pos: begin,
+        });
+    }
+
+    tokens
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token<'a>>,
+    cur: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.cur).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let tok = self.peek();
+        self.cur += 1;
+        tok
+    }
+
+    fn peek_is(&self, words: &[&str]) -> bool {
+        self.peek().is_some_and(|t| words.contains(&t.text))
+    }
+
+    fn error(&self, pos: usize, message: String) -> ParseError {
+        ParseError {
+            expr: self.src.to_string(),
+            pos,
+            message,
+        }
+    }
+
+    // expects an operand of `what`, reporting the end of the expression if missing
+    fn expect(&mut self, what: &str) -> Result<Token<'a>, ParseError> {
+        self.next()
+            .ok_or_else(|| self.error(self.src.len(), format!("expected {}", what)))
+    }
+
+    // `and` and `or` have the same precedence and are left associative,
+    // e.g. `tcp or udp and port 53` is `(tcp or udp) and port 53`
+    fn parse_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_not()?;
+        loop {
+            if self.peek_is(&["and", "&&"]) {
+                self.next();
+                expr = Expr::and(expr, self.parse_not()?);
+            } else if self.peek_is(&["or", "||"]) {
+                self.next();
+                expr = Expr::Or(Box::new(expr), Box::new(self.parse_not()?));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        if self.peek_is(&["not", "!"]) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_primitive()
+    }
+
+    fn parse_primitive(&mut self) -> Result<Expr, ParseError> {
+        let tok = self.expect("a primitive")?;
+        match tok.text {
+            "(" => {
+                let expr = self.parse_expr()?;
+                match self.next() {
+                    Some(t) if t.text == ")" => Ok(expr),
+                    Some(t) => {
+                        Err(self.error(t.pos, format!("expected ')' but found '{}'", t.text)))
+                    }
+                    None => Err(self.error(self.src.len(), "missing ')'".to_string())),
+                }
+            }
+            "ip" => Ok(Expr::Ip),
+            "tcp" | "udp" => {
+                let proto = Expr::Proto(if tok.text == "tcp" {
+                    Proto::TCP
+                } else {
+                    Proto::UDP
+                });
+                // e.g. `tcp dst port 80`
+                if self.peek_is(&["src", "dst", "port", "portrange"]) {
+                    let dir = self.parse_dir();
+                    let qualifier = self.expect("'port' or 'portrange'")?;
+                    match qualifier.text {
+                        "port" | "portrange" => {
+                            Ok(Expr::and(proto, self.parse_port(dir, qualifier)?))
+                        }
+                        _ => Err(self.error(
+                            qualifier.pos,
+                            format!(
+                                "expected 'port' or 'portrange' but found '{}'",
+                                qualifier.text
+                            ),
+                        )),
+                    }
+                } else {
+                    Ok(proto)
+                }
+            }
+            "src" | "dst" | "host" | "net" | "port" | "portrange" => {
+                self.cur -= 1;
+                let dir = self.parse_dir();
+                let qualifier = self.expect("'host', 'net', 'port' or 'portrange'")?;
+                match qualifier.text {
+                    "host" => {
+                        let addr = self.expect("an address")?;
+                        let host = Ipv4Addr::from_str(addr.text).map_err(|_| {
+                            self.error(addr.pos, format!("invalid address '{}'", addr.text))
+                        })?;
+                        Ok(Expr::Host(dir, host))
+                    }
+                    "net" => {
+                        let cidr = self.expect("a cidr")?;
+                        let net = Ipv4Network::from_str(cidr.text).map_err(|_| {
+                            self.error(cidr.pos, format!("invalid cidr '{}'", cidr.text))
+                        })?;
+                        Ok(Expr::Net(dir, net))
+                    }
+                    "port" | "portrange" => self.parse_port(dir, qualifier),
+                    _ => Err(self.error(
+                        qualifier.pos,
+                        format!(
+                            "expected 'host', 'net', 'port' or 'portrange' but found '{}'",
+                            qualifier.text
+                        ),
+                    )),
+                }
+            }
+            "inbound" => Ok(Expr::Traffic(Flow::Ingress)),
+            "outbound" => Ok(Expr::Traffic(Flow::Egress)),
+            "less" | "greater" => {
+                let len = self.expect("a length")?;
+                let value = len
+                    .text
+                    .parse::<u16>()
+                    .map_err(|_| self.error(len.pos, format!("invalid length '{}'", len.text)))?;
+                Ok(if tok.text == "less" {
+                    Expr::Less(value)
+                } else {
+                    Expr::Greater(value)
+                })
+            }
+            _ => Err(self.error(tok.pos, format!("unknown primitive '{}'", tok.text))),
+        }
+    }
+
+    fn parse_dir(&mut self) -> Dir {
+        let dir = match self.peek().map(|t| t.text) {
+            Some("src") => Dir::Src,
+            Some("dst") => Dir::Dst,
+            _ => return Dir::Any,
+        };
+        self.next();
+        dir
+    }
+
+    fn parse_port(&mut self, dir: Dir, qualifier: Token<'a>) -> Result<Expr, ParseError> {
+        let port = self.expect(if qualifier.text == "port" {
+            "a port"
+        } else {
+            "a port range"
+        })?;
+        let range =
+            PortRange::from_str(port.text).map_err(|e| self.error(port.pos, e.to_string()))?;
+        if qualifier.text == "port" && range.as_single().is_none() {
+            return Err(self.error(
+                port.pos,
+                format!("'{}' is a port range, use 'portrange' instead", port.text),
+            ));
+        }
+        Ok(Expr::Port(dir, range))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use network_types::ip::IpProto;
+    use sniff_common::Flow;
+
+    use super::Expr;
+    use crate::network::{self, NetworkPacket};
+
+    fn packet(flow: Flow, src: [u8; 4], source: u16, dst: [u8; 4], dest: u16) -> NetworkPacket {
+        network::test::packet((src, source), (dst, dest))
+            .with_proto(IpProto::Tcp)
+            .with_flow(flow)
+            .build()
+    }
+
+    #[test]
+    fn test_match_expr() {
+        let expr: Expr = "tcp and dst port 443 and not net 10.0.0.0/8"
+            .parse()
+            .unwrap();
+        let pkt = packet(Flow::Egress, [10, 0, 0, 1], 50000, [1, 1, 1, 1], 443);
+        assert!(!expr.matches(&pkt));
+        let pkt = packet(Flow::Egress, [192, 168, 0, 1], 50000, [1, 1, 1, 1], 443);
+        assert!(expr.matches(&pkt));
+
+        let expr: Expr = "outbound && (udp || tcp src portrange 1-1024)"
+            .parse()
+            .unwrap();
+        let pkt = packet(Flow::Egress, [1, 1, 1, 1], 443, [10, 0, 0, 1], 50000);
+        assert!(expr.matches(&pkt));
+        let pkt = packet(Flow::Ingress, [1, 1, 1, 1], 443, [10, 0, 0, 1], 50000);
+        assert!(!expr.matches(&pkt));
+
+        let expr: Expr = "!host 1.1.1.1 and greater 40".parse().unwrap();
+        let pkt = packet(Flow::Ingress, [1, 1, 1, 1], 443, [10, 0, 0, 1], 50000);
+        assert!(!expr.matches(&pkt));
+
+        let expr: Expr = "tcp&&!dst port 80||udp".parse().unwrap();
+        let pkt = packet(Flow::Egress, [10, 0, 0, 1], 50000, [1, 1, 1, 1], 443);
+        assert!(expr.matches(&pkt));
+    }
+
+    #[test]
+    fn test_expr_precedence() {
+        // `and` and `or` are evaluated from left to right, as `(tcp or udp) and port 53`
+        let expr: Expr = "tcp or udp and port 53".parse().unwrap();
+        let pkt = packet(Flow::Egress, [10, 0, 0, 1], 50000, [1, 1, 1, 1], 80);
+        assert!(!expr.matches(&pkt));
+        let pkt = packet(Flow::Egress, [10, 0, 0, 1], 50000, [1, 1, 1, 1], 53);
+        assert!(expr.matches(&pkt));
+
+        let expr: Expr = "port 53 and udp or tcp".parse().unwrap();
+        let pkt = packet(Flow::Egress, [10, 0, 0, 1], 50000, [1, 1, 1, 1], 80);
+        assert!(expr.matches(&pkt));
+    }
+
+    #[test]
+    fn test_parse_expr_error() {
+        let err = "tcp and dst prot 443".parse::<Expr>().unwrap_err();
+        assert_eq!(err.pos, 12);
+        assert!(err
+            .to_string()
+            .ends_with("\n  tcp and dst prot 443\n              ^"));
+
+        // the caret is placed by characters rather than bytes
+        let err = "tcp\u{a0}and dst prot 443".parse::<Expr>().unwrap_err();
+        assert_eq!(err.pos, 13);
+        assert!(err.to_string().contains(" at column 13 of"));
+        assert!(err.to_string().ends_with("\n              ^"));
+
+        let err = "tcp and dst port".parse::<Expr>().unwrap_err();
+        assert_eq!(err.pos, 16);
+        assert_eq!(err.message, "expected a port");
+
+        let err = "(tcp or udp".parse::<Expr>().unwrap_err();
+        assert_eq!(err.message, "missing ')'");
+
+        let err = "port 80-90".parse::<Expr>().unwrap_err();
+        assert_eq!(err.pos, 5);
+        assert!("tcp port http".parse::<Expr>().is_ok());
+        assert!("tcp udp".parse::<Expr>().is_err());
+    }
+}
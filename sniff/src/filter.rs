@@ -1,47 +1,171 @@
 use std::{
     collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    str::FromStr,
     sync::Arc,
 };
 
+use ipnetwork::Ipv4Network;
+use serde::Deserialize;
 use sniff_common::Flow;
 
 use crate::{
     config::ConfigItem,
+    expr::Expr,
+    iface::IfaceSet,
+    netns::{self, Netns},
     network::{NetworkPacket, Proto},
+    port::PortSet,
 };
 
+/// Decides which rules count a packet when several of them match it
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatchPolicy {
+    /// only the first matching rule in configuration order
+    #[serde(alias = "first")]
+    First,
+    /// only the matching rule with the longest cidr prefix
+    #[serde(alias = "longest")]
+    #[default]
+    Longest,
+    /// every matching rule
+    #[serde(alias = "all")]
+    All,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Filter {
     name: String,
+    /// position of the rule in the configuration
+    pub index: usize,
     pub protocol: Proto,
-    pub in_port_filter: HashSet<u16>,
-    pub in_iface_filter: HashSet<String>,
-    pub out_iface_filter: HashSet<String>,
+    pub cidrs: Vec<Ipv4Network>,
+    pub src_cidrs: Vec<Ipv4Network>,
+    pub dst_cidrs: Vec<Ipv4Network>,
+    pub exclude_cidrs: Vec<Ipv4Network>,
+    pub in_port_filter: PortSet,
+    pub out_port_filter: PortSet,
+    pub src_port_filter: PortSet,
+    pub dst_port_filter: PortSet,
+    pub in_iface_filter: IfaceSet,
+    pub out_iface_filter: IfaceSet,
+    pub exclude_port_filter: PortSet,
+    pub exclude_iface_filter: IfaceSet,
+    /// the network namespace of the interfaces, `None` for the namespace of netsniff
+    pub netns: Option<Netns>,
     pub label_values: Arc<HashMap<String, String>>,
+    pub tls_ports: HashSet<u16>,
+    pub sni_label: bool,
+    pub http_ports: HashSet<u16>,
+    /// filter expression of the rule, see [Expr]
+    pub matcher: Option<Expr>,
 
     pass: bool,
 }
 
 impl From<ConfigItem> for Filter {
     fn from(value: ConfigItem) -> Self {
+        let cidrs = if let Some(cidrs) = value.cidrs {
+            cidrs
+                .iter()
+                .filter_map(|c| Ipv4Network::from_str(c).ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let src_cidrs = if let Some(cidrs) = value.src_cidrs {
+            cidrs
+                .iter()
+                .filter_map(|c| Ipv4Network::from_str(c).ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let dst_cidrs = if let Some(cidrs) = value.dst_cidrs {
+            cidrs
+                .iter()
+                .filter_map(|c| Ipv4Network::from_str(c).ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let exclude_cidrs = if let Some(cidrs) = value.exclude_cidrs {
+            cidrs
+                .iter()
+                .filter_map(|c| Ipv4Network::from_str(c).ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         let in_port_filter = if let Some(ports) = value.in_ports {
             ports.into_iter().collect()
         } else {
-            HashSet::new()
+            PortSet::default()
+        };
+
+        let out_port_filter = if let Some(ports) = value.out_ports {
+            ports.into_iter().collect()
+        } else {
+            PortSet::default()
+        };
+
+        let src_port_filter = if let Some(ports) = value.src_ports {
+            ports.into_iter().collect()
+        } else {
+            PortSet::default()
+        };
+
+        let dst_port_filter = if let Some(ports) = value.dst_ports {
+            ports.into_iter().collect()
+        } else {
+            PortSet::default()
         };
 
+        // invalid selectors are reported by the configuration check
         let in_iface_filter = if let Some(ifaces) = value.in_iface {
-            ifaces.into_iter().collect()
+            IfaceSet::parse(ifaces).unwrap_or_default()
         } else {
-            HashSet::new()
+            IfaceSet::default()
         };
 
         let out_iface_filter = if let Some(ifaces) = value.out_iface {
-            ifaces.into_iter().collect()
+            IfaceSet::parse(ifaces).unwrap_or_default()
+        } else {
+            IfaceSet::default()
+        };
+
+        let exclude_port_filter = if let Some(ports) = value.exclude_ports {
+            ports.into_iter().collect()
+        } else {
+            PortSet::default()
+        };
+
+        let exclude_iface_filter = if let Some(ifaces) = value.exclude_iface {
+            IfaceSet::parse(ifaces).unwrap_or_default()
+        } else {
+            IfaceSet::default()
+        };
+
+        let netns = value.netns.and_then(|n| n.parse().ok());
+
+        let tls_ports = if let Some(ports) = value.tls_ports {
+            ports.into_iter().collect()
         } else {
             HashSet::new()
         };
 
+        let http_ports = if let Some(ports) = value.http_ports {
+            ports.into_iter().collect()
+        } else {
+            HashSet::new()
+        };
+
+        let matcher = value.match_expr.and_then(|e| e.parse().ok());
+
         let label_values = if let Some(lv) = value.const_values {
             Arc::new(lv)
         } else {
@@ -50,11 +174,26 @@ impl From<ConfigItem> for Filter {
 
         Self {
             name: value.name,
+            index: 0,
             protocol: value.protocol,
+            cidrs,
+            src_cidrs,
+            dst_cidrs,
+            exclude_cidrs,
             in_port_filter,
+            out_port_filter,
+            src_port_filter,
+            dst_port_filter,
             in_iface_filter,
             out_iface_filter,
+            exclude_port_filter,
+            exclude_iface_filter,
+            netns,
             label_values,
+            tls_ports,
+            sni_label: value.sni_label,
+            http_ports,
+            matcher,
             pass: false,
         }
     }
@@ -63,9 +202,9 @@ impl From<ConfigItem> for Filter {
 impl Filter {
     pub fn filter(&self, pkt: &NetworkPacket) -> (bool, Option<&HashMap<String, String>>) {
         if self.pass {
-            return (true, None);
+            return (self.match_expr(pkt), None);
         }
-        if !self.match_iface(&pkt.iface, &pkt.flow) {
+        if !self.match_iface(&pkt.iface, &pkt.netns, &pkt.flow) {
             return (false, None);
         }
 
@@ -73,6 +212,22 @@ impl Filter {
             return (false, None);
         }
 
+        if !self.match_src_dst_port(pkt.pkt.source, pkt.pkt.dst) {
+            return (false, None);
+        }
+
+        if !self.match_src_dst_cidr(pkt.pkt.src_ip, pkt.pkt.dst_ip) {
+            return (false, None);
+        }
+
+        if self.match_exclude(pkt) {
+            return (false, None);
+        }
+
+        if !self.match_expr(pkt) {
+            return (false, None);
+        }
+
         // TODO: add more matching rules
         (true, None)
     }
@@ -81,7 +236,11 @@ impl Filter {
         self.name.to_owned()
     }
 
-    fn match_iface(&self, iface: &String, flow: &Flow) -> bool {
+    fn match_iface(&self, iface: &str, netns: &str, flow: &Flow) -> bool {
+        if netns::label(self.netns.as_ref()) != netns {
+            return false;
+        }
+
         match flow {
             Flow::Ingress => {
                 if self.in_iface_filter.is_empty() {
@@ -104,15 +263,75 @@ impl Filter {
 
     fn match_port(&self, port: u16, flow: &Flow) -> bool {
         match flow {
-            Flow::Ingress => !self.enable_port() || self.in_port_filter.contains(&port),
-            Flow::Egress => true,
+            Flow::Ingress => !self.enable_port(flow) || self.in_port_filter.contains(port),
+            Flow::Egress => !self.enable_port(flow) || self.out_port_filter.contains(port),
             /* shouldn't go to this branch. */
             Flow::All => true,
         }
     }
 
-    pub fn enable_port(&self) -> bool {
-        !self.in_port_filter.is_empty()
+    /// Returns the most specific cidr of the rule containing the peer address,
+    /// or `any` if the rule does not match on cidrs.
+    pub fn peer_cidr(&self, addr: Ipv4Addr) -> String {
+        self.cidrs
+            .iter()
+            .filter(|cidr| cidr.contains(addr))
+            .max_by_key(|cidr| cidr.prefix())
+            .map(|cidr| cidr.to_string())
+            .unwrap_or_else(|| "any".to_string())
+    }
+
+    /// Unlike [Filter::match_port], source and destination ports are matched regardless of the traffic direction
+    fn match_src_dst_port(&self, source: u16, dst: u16) -> bool {
+        (self.src_port_filter.is_empty() || self.src_port_filter.contains(source))
+            && (self.dst_port_filter.is_empty() || self.dst_port_filter.contains(dst))
+    }
+
+    /// Unlike `cidrs` which match the peer address, source and destination cidrs
+    /// are matched against both sides of the packet regardless of the traffic direction
+    fn match_src_dst_cidr(&self, src_ip: Ipv4Addr, dst_ip: Ipv4Addr) -> bool {
+        (self.src_cidrs.is_empty() || self.src_cidrs.iter().any(|c| c.contains(src_ip)))
+            && (self.dst_cidrs.is_empty() || self.dst_cidrs.iter().any(|c| c.contains(dst_ip)))
+    }
+
+    fn match_expr(&self, pkt: &NetworkPacket) -> bool {
+        self.matcher.as_ref().is_none_or(|expr| expr.matches(pkt))
+    }
+
+    /// Whether the packet is carved out of the rule by one of its exclusions.
+    ///
+    /// Excluded cidrs are matched against the peer address, excluded ports against both ports.
+    fn match_exclude(&self, pkt: &NetworkPacket) -> bool {
+        if self.exclude_iface_filter.contains(&pkt.iface) {
+            return true;
+        }
+
+        if self.exclude_port_filter.contains(pkt.pkt.source)
+            || self.exclude_port_filter.contains(pkt.pkt.dst)
+        {
+            return true;
+        }
+
+        let peer = match pkt.flow {
+            Flow::Ingress => pkt.pkt.src_ip,
+            Flow::Egress => pkt.pkt.dst_ip,
+            Flow::All => return false,
+        };
+        self.exclude_cidrs.iter().any(|cidr| cidr.contains(peer))
+    }
+
+    /// Whether the rule matches on the destination port of the given traffic direction
+    pub fn enable_port(&self, flow: &Flow) -> bool {
+        match flow {
+            Flow::Ingress => !self.in_port_filter.is_empty(),
+            Flow::Egress => !self.out_port_filter.is_empty(),
+            Flow::All => false,
+        }
+    }
+
+    /// Whether the filter matches packets regardless of the rule fields, as in the command line mode
+    pub fn is_pass(&self) -> bool {
+        self.pass
     }
 
     pub fn default_pass_filter() -> Filter {
@@ -121,4 +340,152 @@ impl Filter {
             ..Default::default()
         }
     }
+
+    pub fn with_index(mut self, index: usize) -> Self {
+        self.index = index;
+        self
+    }
+
+    pub fn with_matcher(mut self, expr: Expr) -> Self {
+        self.matcher = Some(expr);
+        self
+    }
+
+    pub fn with_tls_ports(mut self, ports: &[u16]) -> Self {
+        self.tls_ports.extend(ports);
+        self
+    }
+
+    pub fn with_http_ports(mut self, ports: &[u16]) -> Self {
+        self.http_ports.extend(ports);
+        self
+    }
+}
+
+/// Fixtures shared by the tests of the other modules
+#[cfg(test)]
+pub(crate) mod test {
+    use sniff_common::Flow;
+
+    use super::Filter;
+    use crate::{
+        config::ConfigItem,
+        network::{self, NetworkPacket},
+    };
+
+    /// Build the filter of a rule of the YAML
+    pub fn filter(yaml: &str) -> Filter {
+        Filter::from(serde_yaml::from_str::<ConfigItem>(yaml).unwrap())
+    }
+
+    fn packet(iface: &str, flow: Flow, src: ([u8; 4], u16), dst: ([u8; 4], u16)) -> NetworkPacket {
+        network::test::packet(src, dst)
+            .with_iface(iface)
+            .with_flow(flow)
+            .build()
+    }
+
+    #[test]
+    fn test_filter_src_dst_ports() {
+        let dns = filter(
+            "{name: dns, inIface: [eth0], outIface: [eth0], srcPorts: [5353], dstPorts: [50-60]}",
+        );
+        let (client, server) = ([10, 0, 0, 1], [10, 0, 0, 53]);
+
+        // the ports are matched regardless of the traffic direction
+        let query = packet("eth0", Flow::Ingress, (client, 5353), (server, 53));
+        assert!(dns.filter(&query).0);
+        let query = packet("eth0", Flow::Egress, (client, 5353), (server, 53));
+        assert!(dns.filter(&query).0);
+
+        let response = packet("eth0", Flow::Ingress, (server, 53), (client, 5353));
+        assert!(!dns.filter(&response).0);
+        let other = packet("eth0", Flow::Egress, (client, 5353), (server, 80));
+        assert!(!dns.filter(&other).0);
+    }
+
+    #[test]
+    fn test_filter_exclude() {
+        let web = filter(
+            "{name: web, inIface: [eth0, eth1], excludeIface: [eth1], excludePorts: [22], excludeCidrs: [10.0.0.0/8]}",
+        );
+        let local = [192, 168, 0, 1];
+
+        assert!(
+            web.filter(&packet(
+                "eth0",
+                Flow::Ingress,
+                ([1, 1, 1, 1], 40000),
+                (local, 80)
+            ))
+            .0
+        );
+        assert!(
+            !web.filter(&packet(
+                "eth1",
+                Flow::Ingress,
+                ([1, 1, 1, 1], 40000),
+                (local, 80)
+            ))
+            .0
+        );
+
+        // excluded ports are matched against both ports
+        assert!(
+            !web.filter(&packet(
+                "eth0",
+                Flow::Ingress,
+                ([1, 1, 1, 1], 40000),
+                (local, 22)
+            ))
+            .0
+        );
+        assert!(
+            !web.filter(&packet(
+                "eth0",
+                Flow::Ingress,
+                ([1, 1, 1, 1], 22),
+                (local, 80)
+            ))
+            .0
+        );
+
+        // excluded cidrs are matched against the peer address
+        assert!(
+            !web.filter(&packet(
+                "eth0",
+                Flow::Ingress,
+                ([10, 1, 2, 3], 40000),
+                (local, 80)
+            ))
+            .0
+        );
+        assert!(
+            web.filter(&packet(
+                "eth0",
+                Flow::Ingress,
+                ([1, 1, 1, 1], 40000),
+                ([10, 0, 0, 1], 80)
+            ))
+            .0
+        );
+    }
+
+    #[test]
+    fn test_filter_src_dst_cidrs() {
+        let db = filter(
+            "{name: db, inIface: [eth0], outIface: [eth0], srcCidrs: [10.0.0.0/8], dstCidrs: [192.168.0.0/16]}",
+        );
+        let (src, dst) = (([10, 0, 0, 1], 40000), ([192, 168, 1, 1], 5432));
+
+        // the cidrs are matched regardless of the traffic direction
+        assert!(db.filter(&packet("eth0", Flow::Ingress, src, dst)).0);
+        assert!(db.filter(&packet("eth0", Flow::Egress, src, dst)).0);
+
+        assert!(!db.filter(&packet("eth0", Flow::Ingress, dst, src)).0);
+        assert!(
+            !db.filter(&packet("eth0", Flow::Egress, src, ([172, 16, 0, 1], 5432)))
+                .0
+        );
+    }
 }
@@ -0,0 +1,508 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use sniff_common::Flow;
+
+use crate::{
+    metrics,
+    network::NetworkPacket,
+    tcp::{TcpEvent, TcpState},
+};
+
+/// Identifies a transport flow, both directions of a connection share the same key.
+///
+/// The same addresses may be used in several network namespaces, e.g. 127.0.0.1.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    netns: String,
+    proto: u8,
+    lo: (Ipv4Addr, u16),
+    hi: (Ipv4Addr, u16),
+}
+
+impl From<&NetworkPacket> for FlowKey {
+    fn from(net_pkt: &NetworkPacket) -> Self {
+        let pkt = &net_pkt.pkt;
+        let src = (pkt.src_ip, pkt.source);
+        let dst = (pkt.dst_ip, pkt.dst);
+        let (lo, hi) = if src <= dst { (src, dst) } else { (dst, src) };
+
+        Self {
+            netns: net_pkt.netns.to_owned(),
+            proto: pkt.proto as u8,
+            lo,
+            hi,
+        }
+    }
+}
+
+/// The attributes attached to a flow, learned from the packets seen so far.
+#[derive(Debug, Clone)]
+pub struct FlowEntry {
+    pub server_name: Option<String>,
+    pub alpn: Option<String>,
+
+    pub tcp_state: Option<TcpState>,
+    /// the time the first handshake segment was seen
+    pub opened_at: Option<Instant>,
+    /// the rule, network interface and network namespace the connection was first matched on
+    pub owner: Option<(String, String, String)>,
+    /// sample rate of the interface the connection was first seen on
+    pub sample_rate: u32,
+    /// the next expected sequence number of each direction, and the network
+    /// interface and traffic direction its segments are observed on
+    next_seq: [Option<(u32, String, Flow)>; 2],
+    /// whether each direction has sent its FIN
+    fin: [bool; 2],
+
+    last_seen: Instant,
+}
+
+impl Default for FlowEntry {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            alpn: None,
+            tcp_state: None,
+            opened_at: None,
+            owner: None,
+            sample_rate: 1,
+            next_seq: [None, None],
+            fin: [false; 2],
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+/// The number of tracked connections by rule, network interface, network namespace and state
+pub type TcpStates = HashMap<(String, String, String, TcpState), i64>;
+
+/// The result of tracking a TCP segment
+#[derive(Debug, Default)]
+pub struct TcpUpdate {
+    pub events: Vec<TcpEvent>,
+    /// the time between the SYN and the ACK completing the handshake
+    pub handshake_rtt: Option<Duration>,
+    /// whether the segment carries data that was already sent
+    pub retransmission: bool,
+}
+
+/// Keeps track of the attributes of active flows.
+///
+/// Entries are only created when an attribute is learned,
+/// and are dropped once the flow has been idle for [FlowTable::IDLE_TIMEOUT].
+#[derive(Debug, Default)]
+pub struct FlowTable {
+    flows: Mutex<HashMap<FlowKey, FlowEntry>>,
+}
+
+impl FlowTable {
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+    const HALF_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+    const MAX_FLOWS: usize = 65536;
+
+    /// Update the entry of the flow the packet belongs to, creating it if needed.
+    ///
+    /// Returns `None` without creating the entry once the table is full,
+    /// the idle flows are only dropped by [FlowTable::sweep].
+    pub fn update<F, R>(&self, net_pkt: &NetworkPacket, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut FlowEntry) -> R,
+    {
+        let mut flows = self.flows.lock().unwrap();
+        let key = FlowKey::from(net_pkt);
+        if flows.len() >= Self::MAX_FLOWS && !flows.contains_key(&key) {
+            return None;
+        }
+
+        let entry = flows.entry(key).or_default();
+        entry.last_seen = Instant::now();
+        Some(f(entry))
+    }
+
+    /// Advance the TCP state machine of the connection the packet belongs to,
+    /// returning the lifecycle events the packet produced.
+    ///
+    /// Connections are only tracked from their handshake on, segments of
+    /// unknown connections only produce the events of their own flags.
+    ///
+    /// Packets randomly sampled, i.e. without `sample_flow_hash`, produce nothing:
+    /// the segments of a connection are kept independently, so its handshake
+    /// and sequence numbers are rarely seen in full.
+    pub fn track_tcp(
+        &self,
+        net_pkt: &NetworkPacket,
+        rule_name: &str,
+        sample_flow_hash: bool,
+    ) -> TcpUpdate {
+        let pkt = &net_pkt.pkt;
+        let (Some(flags), Some((seq, _))) = (pkt.tcp_flags, pkt.tcp_seq) else {
+            return TcpUpdate::default();
+        };
+        if net_pkt.sample_rate > 1 && !sample_flow_hash {
+            return TcpUpdate::default();
+        }
+
+        let key = FlowKey::from(net_pkt);
+        let dir = ((pkt.src_ip, pkt.source) != key.lo) as usize;
+        let mut flows = self.flows.lock().unwrap();
+        // once the table is full, new connections are not tracked until idle ones are swept
+        if !flows.contains_key(&key) && (!flags.syn || flows.len() >= Self::MAX_FLOWS) {
+            return TcpUpdate {
+                events: TcpState::transition(None, flags).1,
+                ..Default::default()
+            };
+        }
+
+        let now = Instant::now();
+        let entry = flows.entry(key).or_default();
+        // the 4-tuple of a closed connection may be reused before it is swept
+        if flags.syn && matches!(entry.tcp_state, None | Some(TcpState::Closed)) {
+            *entry = FlowEntry::default();
+        }
+        entry.last_seen = now;
+        if entry.tcp_state.is_none() {
+            entry.opened_at = Some(now);
+            entry.owner = Some((
+                rule_name.to_string(),
+                net_pkt.iface.to_owned(),
+                net_pkt.netns.to_owned(),
+            ));
+            entry.sample_rate = net_pkt.sample_rate;
+        }
+
+        let (mut next, mut events) = TcpState::transition(entry.tcp_state, flags);
+        if flags.fin && !flags.rst {
+            // a retransmitted FIN is not a new event, the connection is
+            // only closed once both directions have sent their FIN
+            if entry.fin[dir] {
+                events.retain(|event| *event != TcpEvent::Fin);
+            }
+            entry.fin[dir] = true;
+            if entry.fin == [true; 2] {
+                next = Some(TcpState::Closed);
+            }
+        }
+        entry.tcp_state = next;
+
+        let handshake_rtt = events
+            .contains(&TcpEvent::Established)
+            .then(|| entry.opened_at.map(|at| now.duration_since(at)))
+            .flatten();
+
+        // a segment whose data ends before the next expected sequence number was already sent
+        let mut retransmission = false;
+        if pkt.payload_len > 0 {
+            let end = seq.wrapping_add(pkt.payload_len as u32);
+            match &entry.next_seq[dir] {
+                // the same segments are seen again on another interface or traffic direction,
+                // e.g. forwarded traffic, only those of the first observation point are compared
+                Some((_, iface, flow)) if *iface != net_pkt.iface || *flow != net_pkt.flow => {}
+                // a keepalive carries a single byte right before the next expected sequence number
+                Some((next, ..)) if pkt.payload_len == 1 && seq == next.wrapping_sub(1) => {}
+                Some((next, ..)) if !seq_after(end, *next) => retransmission = true,
+                _ => entry.next_seq[dir] = Some((end, net_pkt.iface.to_owned(), net_pkt.flow)),
+            }
+        }
+
+        TcpUpdate {
+            events,
+            handshake_rtt,
+            retransmission,
+        }
+    }
+
+    /// Drop the idle and closed flows,
+    /// returning the connections whose handshake did not complete in time.
+    pub fn sweep(&self) -> Vec<FlowEntry> {
+        let now = Instant::now();
+        let mut half_open = Vec::new();
+        self.flows.lock().unwrap().retain(|_, entry| {
+            if entry.tcp_state.is_some_and(|s| s.half_open())
+                && entry
+                    .opened_at
+                    .is_some_and(|at| now.duration_since(at) >= Self::HALF_OPEN_TIMEOUT)
+            {
+                half_open.push(entry.clone());
+                return false;
+            }
+
+            entry.tcp_state != Some(TcpState::Closed)
+                && now.duration_since(entry.last_seen) < Self::IDLE_TIMEOUT
+        });
+
+        half_open
+    }
+
+    /// Count the tracked connections by rule, network interface, network namespace
+    /// and state, scaled by their sample rate
+    pub fn tcp_states(&self) -> TcpStates {
+        let mut states = HashMap::new();
+        for entry in self.flows.lock().unwrap().values() {
+            if let (Some(state), Some((rule_name, iface, netns))) = (entry.tcp_state, &entry.owner)
+            {
+                *states
+                    .entry((
+                        rule_name.to_owned(),
+                        iface.to_owned(),
+                        netns.to_owned(),
+                        state,
+                    ))
+                    .or_default() += entry.sample_rate as i64;
+            }
+        }
+
+        states
+    }
+
+    /// Periodically sweep the table and export the connection lifecycle metrics
+    pub async fn expire(&self, interval: Duration) {
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            debug!("trigger flow table sweep cycle");
+
+            for entry in self.sweep() {
+                if let Some((rule_name, iface, netns)) = &entry.owner {
+                    metrics::inc_tcp_event(
+                        &[rule_name, iface, netns, TcpEvent::HalfOpen.as_str()],
+                        entry.sample_rate as u64,
+                    );
+                }
+            }
+            metrics::set_tcp_connections(&self.tcp_states());
+        }
+    }
+
+    /// Returns a snapshot of the entry of the flow the packet belongs to, if any.
+    pub fn get(&self, net_pkt: &NetworkPacket) -> Option<FlowEntry> {
+        let mut flows = self.flows.lock().unwrap();
+        let entry = flows.get_mut(&FlowKey::from(net_pkt))?;
+        entry.last_seen = Instant::now();
+
+        Some(entry.clone())
+    }
+}
+
+/// Compare sequence numbers taking the wrap around into account
+#[inline]
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use network_types::ip::IpProto;
+    use sniff_common::Flow;
+
+    use super::{FlowEntry, FlowKey, FlowTable};
+    use crate::{
+        network::{self, NetworkPacket},
+        tcp::{TcpEvent, TcpState},
+    };
+
+    /// A segment of the connection between 10.0.0.1:40000 and 10.0.0.2:443
+    fn segment(from_client: bool, flags: &str, seq: u32, payload_len: u16) -> NetworkPacket {
+        observed(from_client, flags, seq, payload_len, Flow::Egress)
+    }
+
+    fn observed(
+        from_client: bool,
+        flags: &str,
+        seq: u32,
+        payload_len: u16,
+        flow: Flow,
+    ) -> NetworkPacket {
+        let (client, server) = (([10, 0, 0, 1], 40000), ([10, 0, 0, 2], 443));
+        let (src, dst) = if from_client {
+            (client, server)
+        } else {
+            (server, client)
+        };
+
+        network::test::packet(src, dst)
+            .with_iface("eth0")
+            .with_flow(flow)
+            .with_tcp(flags, (seq, 0))
+            .with_payload_len(payload_len)
+            .build()
+    }
+
+    fn state(table: &FlowTable) -> Option<TcpState> {
+        table
+            .flows
+            .lock()
+            .unwrap()
+            .values()
+            .next()
+            .and_then(|e| e.tcp_state)
+    }
+
+    #[test]
+    fn test_track_close() {
+        let table = FlowTable::default();
+        table.track_tcp(&segment(true, "S", 0, 0), "rule", false);
+        table.track_tcp(&segment(false, "S.", 0, 0), "rule", false);
+        table.track_tcp(&segment(true, ".", 1, 0), "rule", false);
+
+        let update = table.track_tcp(&segment(true, "F.", 1, 0), "rule", false);
+        assert_eq!(update.events, vec![TcpEvent::Fin]);
+        assert_eq!(state(&table), Some(TcpState::Closing));
+
+        // a retransmitted FIN of the same side neither counts nor closes the connection
+        let update = table.track_tcp(&segment(true, "F.", 1, 0), "rule", false);
+        assert!(update.events.is_empty());
+        assert_eq!(state(&table), Some(TcpState::Closing));
+
+        let update = table.track_tcp(&segment(false, "F.", 1, 0), "rule", false);
+        assert_eq!(update.events, vec![TcpEvent::Fin]);
+        assert_eq!(state(&table), Some(TcpState::Closed));
+    }
+
+    #[test]
+    fn test_track_port_reuse() {
+        let table = FlowTable::default();
+        table.track_tcp(&segment(true, "S", 0, 0), "rule", false);
+        table.track_tcp(&segment(false, "S.", 0, 0), "rule", false);
+        table.track_tcp(&segment(true, ".", 1, 0), "rule", false);
+        table.track_tcp(&segment(true, "P.", 1, 100), "rule", false);
+        table.track_tcp(&segment(true, "F.", 101, 0), "rule", false);
+        table.track_tcp(&segment(false, "F.", 1, 0), "rule", false);
+        assert_eq!(state(&table), Some(TcpState::Closed));
+        let first_opened_at = table.get(&segment(true, ".", 0, 0)).unwrap().opened_at;
+
+        // a new connection on the same 4-tuple starts from a fresh entry
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let update = table.track_tcp(&segment(true, "S", 0, 0), "rule", false);
+        assert_eq!(update.events, vec![TcpEvent::Syn]);
+        assert_eq!(state(&table), Some(TcpState::SynSent));
+        let entry = table.get(&segment(true, ".", 0, 0)).unwrap();
+        assert!(entry.opened_at > first_opened_at);
+        assert_eq!(entry.fin, [false; 2]);
+
+        table.track_tcp(&segment(false, "S.", 0, 0), "rule", false);
+        table.track_tcp(&segment(true, ".", 1, 0), "rule", false);
+        let update = table.track_tcp(&segment(true, "P.", 1, 100), "rule", false);
+        assert!(!update.retransmission);
+
+        table.track_tcp(&segment(true, "F.", 101, 0), "rule", false);
+        assert_eq!(state(&table), Some(TcpState::Closing));
+    }
+
+    #[test]
+    fn test_track_capacity() {
+        let table = FlowTable::default();
+        {
+            let mut flows = table.flows.lock().unwrap();
+            for i in 0..FlowTable::MAX_FLOWS as u32 {
+                let key = FlowKey {
+                    netns: String::new(),
+                    proto: IpProto::Tcp as u8,
+                    lo: (Ipv4Addr::from(i), 1),
+                    hi: (Ipv4Addr::from(i), 2),
+                };
+                flows.insert(key, FlowEntry::default());
+            }
+        }
+
+        // the SYN still counts, but the connection is not tracked
+        let update = table.track_tcp(&segment(true, "S", 0, 0), "rule", false);
+        assert_eq!(update.events, vec![TcpEvent::Syn]);
+        assert!(table.update(&segment(true, ".", 1, 0), |_| ()).is_none());
+        assert_eq!(table.flows.lock().unwrap().len(), FlowTable::MAX_FLOWS);
+    }
+
+    #[test]
+    fn test_handshake_rtt() {
+        let table = FlowTable::default();
+        assert!(table
+            .track_tcp(&segment(true, "S", 0, 0), "rule", false)
+            .handshake_rtt
+            .is_none());
+        table.track_tcp(&segment(false, "S.", 0, 0), "rule", false);
+        let update = table.track_tcp(&segment(true, ".", 1, 0), "rule", false);
+        assert_eq!(update.events, vec![TcpEvent::Established]);
+        assert!(update.handshake_rtt.is_some());
+
+        // segments of an established connection do not measure the handshake again
+        let update = table.track_tcp(&segment(true, ".", 1, 0), "rule", false);
+        assert!(update.events.is_empty() && update.handshake_rtt.is_none());
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let table = FlowTable::default();
+        table.track_tcp(&segment(true, "S", 0, 0), "rule", false);
+        table.track_tcp(&segment(false, "S.", 0, 0), "rule", false);
+        table.track_tcp(&segment(true, ".", 1, 0), "rule", false);
+
+        // in order segments of both directions
+        assert!(
+            !table
+                .track_tcp(&segment(true, "P.", 1, 100), "rule", false)
+                .retransmission
+        );
+        assert!(
+            !table
+                .track_tcp(&segment(true, "P.", 101, 100), "rule", false)
+                .retransmission
+        );
+        assert!(
+            !table
+                .track_tcp(&segment(false, "P.", 1, 100), "rule", false)
+                .retransmission
+        );
+
+        // the data of the segment was already sent
+        assert!(
+            table
+                .track_tcp(&segment(true, "P.", 101, 100), "rule", false)
+                .retransmission
+        );
+
+        // a keepalive probes with the byte right before the next sequence number
+        assert!(
+            !table
+                .track_tcp(&segment(true, ".", 200, 1), "rule", false)
+                .retransmission
+        );
+
+        // the forwarded segment is seen again on the ingress
+        let forwarded = observed(true, "P.", 201, 100, Flow::Ingress);
+        assert!(!table.track_tcp(&forwarded, "rule", false).retransmission);
+        assert!(
+            !table
+                .track_tcp(&segment(true, "P.", 201, 100), "rule", false)
+                .retransmission
+        );
+    }
+
+    #[test]
+    fn test_track_sampled() {
+        let table = FlowTable::default();
+        let sampled = |from_client, flags| {
+            let mut net_pkt = segment(from_client, flags, 0, 0);
+            net_pkt.sample_rate = 10;
+            net_pkt
+        };
+
+        // randomly sampled segments do not feed the table
+        for (from_client, flags) in [(true, "S"), (false, "S."), (true, ".")] {
+            let update = table.track_tcp(&sampled(from_client, flags), "rule", false);
+            assert!(update.events.is_empty() && update.handshake_rtt.is_none());
+        }
+        assert!(table.flows.lock().unwrap().is_empty());
+
+        // all the segments of a connection are kept when sampled by flow hash
+        let update = table.track_tcp(&sampled(true, "S"), "rule", true);
+        assert_eq!(update.events, vec![TcpEvent::Syn]);
+        assert_eq!(state(&table), Some(TcpState::SynSent));
+    }
+}
@@ -0,0 +1,155 @@
+use std::{collections::HashSet, fmt::Display};
+
+use network_types::ip::IpProto;
+
+use crate::network::Packet;
+
+const METHODS: [&str; 9] = [
+    "GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// The head of a plain-text HTTP/1.x message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpMessage {
+    Request {
+        method: String,
+        host: Option<String>,
+        path: String,
+    },
+    Response {
+        status: u16,
+    },
+}
+
+/// Decode the HTTP/1.x message head carried by the packet.
+///
+/// Requests are only decoded when sent to one of the given ports,
+/// responses when sent from one of them.
+pub fn decode(pkt: &Packet, ports: &HashSet<u16>) -> Option<HttpMessage> {
+    if !matches!(pkt.proto, IpProto::Tcp) || pkt.payload.is_empty() {
+        return None;
+    }
+
+    if ports.contains(&pkt.dst) {
+        HttpMessage::parse_request(&pkt.payload)
+    } else if ports.contains(&pkt.source) {
+        HttpMessage::parse_response(&pkt.payload)
+    } else {
+        None
+    }
+}
+
+impl HttpMessage {
+    /// Parse the request line and the `Host` header of a request
+    pub fn parse_request(buf: &[u8]) -> Option<Self> {
+        let mut lines = head_lines(buf);
+        let mut parts = lines.next()?.split(' ');
+        let (method, path, version) = (parts.next()?, parts.next()?, parts.next()?);
+        if !METHODS.contains(&method) || !version.starts_with("HTTP/1.") {
+            return None;
+        }
+
+        let host = lines.take_while(|l| !l.is_empty()).find_map(|l| {
+            let (name, value) = l.split_once(':')?;
+            name.eq_ignore_ascii_case("host")
+                .then(|| value.trim().to_string())
+        });
+
+        Some(Self::Request {
+            method: method.to_string(),
+            host,
+            path: path.to_string(),
+        })
+    }
+
+    /// Parse the status line of a response
+    pub fn parse_response(buf: &[u8]) -> Option<Self> {
+        let line = head_lines(buf).next()?;
+        let mut parts = line.split(' ');
+        if !parts.next()?.starts_with("HTTP/1.") {
+            return None;
+        }
+
+        let status = parts.next()?.parse::<u16>().ok()?;
+        (100..600)
+            .contains(&status)
+            .then_some(Self::Response { status })
+    }
+
+    /// Returns the status class of a response (e.g. `2xx`)
+    pub fn status_class(&self) -> Option<String> {
+        match self {
+            Self::Response { status } => Some(format!("{}xx", status / 100)),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the first segment of a request path, without the query string.
+///
+/// e.g. `/api/v1/users?id=1` => `/api`
+pub fn path_prefix(path: &str) -> &str {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    match path.get(1..).and_then(|p| p.find('/')) {
+        Some(end) => &path[..end + 1],
+        None => path,
+    }
+}
+
+impl Display for HttpMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Request { method, host, path } => write!(
+                f,
+                "  http request  {:<8}{}{}",
+                method,
+                host.as_deref().unwrap_or_default(),
+                path_prefix(path),
+            ),
+            Self::Response { status } => write!(f, "  http response {}", status),
+        }
+    }
+}
+
+/// Iterate over the lines of the message head, the payload may be truncated
+/// so a last incomplete line is still returned.
+fn head_lines(buf: &[u8]) -> impl Iterator<Item = &str> {
+    buf.split(|b| *b == b'\n')
+        .map(|l| l.strip_suffix(b"\r").unwrap_or(l))
+        .map_while(|l| std::str::from_utf8(l).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{path_prefix, HttpMessage};
+
+    #[test]
+    fn test_parse_request() {
+        let buf =
+            b"GET /api/v1/users?id=1 HTTP/1.1\r\nUser-Agent: curl\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            HttpMessage::parse_request(buf),
+            Some(HttpMessage::Request {
+                method: "GET".to_string(),
+                host: Some("example.com".to_string()),
+                path: "/api/v1/users?id=1".to_string(),
+            })
+        );
+        assert!(HttpMessage::parse_request(b"\x16\x03\x01\x02\x00").is_none());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let msg = HttpMessage::parse_response(b"HTTP/1.1 404 Not Found\r\n").unwrap();
+        assert_eq!(msg, HttpMessage::Response { status: 404 });
+        assert_eq!(msg.status_class().as_deref(), Some("4xx"));
+        assert!(HttpMessage::parse_response(b"HTTP/1.1 abc\r\n").is_none());
+    }
+
+    #[test]
+    fn test_path_prefix() {
+        assert_eq!(path_prefix("/api/v1/users?id=1"), "/api");
+        assert_eq!(path_prefix("/health?full"), "/health");
+        assert_eq!(path_prefix("/"), "/");
+    }
+}
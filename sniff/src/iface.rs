@@ -0,0 +1,239 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+use regex::Regex;
+
+/// A network interface selector of the rules and the `-i` flag
+///
+/// * `eth0`: the interface of the name
+/// * `veth*`, `eth?`: the interfaces matching the glob pattern
+/// * `/^veth[0-9a-f]+$/`: the interfaces matching the regular expression
+/// * `!lo`: excludes the interfaces of the selector
+#[derive(Debug, Clone)]
+pub struct IfaceSelector {
+    source: String,
+    exclude: bool,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Name(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+impl FromStr for IfaceSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (exclude, pattern) = match s.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, s),
+        };
+        if pattern.is_empty() {
+            return Err(anyhow!("'{}' is not a valid interface selector", s));
+        }
+
+        let matcher = if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+            let regex = Regex::new(&pattern[1..pattern.len() - 1])
+                .map_err(|e| anyhow!("'{}' is not a valid interface regex by {}", s, e))?;
+            Matcher::Regex(regex)
+        } else if pattern.contains(['*', '?']) {
+            Matcher::Glob(pattern.to_owned())
+        } else {
+            Matcher::Name(pattern.to_owned())
+        };
+
+        Ok(Self {
+            source: s.to_owned(),
+            exclude,
+            matcher,
+        })
+    }
+}
+
+impl IfaceSelector {
+    fn matches(&self, iface: &str) -> bool {
+        match &self.matcher {
+            Matcher::Name(name) => name == iface,
+            Matcher::Glob(pattern) => glob_match(pattern.as_bytes(), iface.as_bytes()),
+            Matcher::Regex(regex) => regex.is_match(iface),
+        }
+    }
+
+    /// Whether the selector is a single interface name
+    pub fn is_name(&self) -> bool {
+        !self.exclude && matches!(self.matcher, Matcher::Name(_))
+    }
+}
+
+/// A set of network interface selectors, an empty set matches no interface.
+///
+/// An interface is matched by the set if it matches an including selector and no
+/// excluding selector, a set of excluding selectors only matches every other interface.
+#[derive(Debug, Clone, Default)]
+pub struct IfaceSet {
+    selectors: Vec<IfaceSelector>,
+}
+
+impl PartialEq for IfaceSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.sources().eq(other.sources())
+    }
+}
+
+impl FromIterator<IfaceSelector> for IfaceSet {
+    fn from_iter<T: IntoIterator<Item = IfaceSelector>>(iter: T) -> Self {
+        Self {
+            selectors: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IfaceSet {
+    pub fn parse<I, S>(selectors: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        selectors.into_iter().map(|s| s.as_ref().parse()).collect()
+    }
+
+    pub fn contains(&self, iface: &str) -> bool {
+        let mut included = None;
+        for selector in self.selectors.iter() {
+            if selector.exclude {
+                if selector.matches(iface) {
+                    return false;
+                }
+            } else {
+                included = Some(included.unwrap_or(false) || selector.matches(iface));
+            }
+        }
+
+        included.unwrap_or(!self.selectors.is_empty())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.selectors.is_empty()
+    }
+
+    /// Returns the interfaces selected by their name
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.selectors.iter().filter_map(|s| match &s.matcher {
+            Matcher::Name(name) if !s.exclude => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    fn sources(&self) -> impl Iterator<Item = &str> + '_ {
+        self.selectors.iter().map(|s| s.source.as_str())
+    }
+}
+
+/// The sample rates by `netns` label and network interface selector
+pub type SampleRates = HashMap<(String, String), u32>;
+
+/// Returns the sample rate of the network interface in the namespace of the `netns` label,
+/// the rate of its name or the lowest rate of the selectors matching it.
+pub fn sample_rate(rates: &SampleRates, netns: &str, iface: &str) -> u32 {
+    if let Some(rate) = rates.get(&(netns.to_owned(), iface.to_owned())) {
+        return *rate;
+    }
+
+    rates
+        .iter()
+        .filter(|((ns, _), _)| ns == netns)
+        .filter(
+            |((_, selector), _)| match selector.parse::<IfaceSelector>() {
+                // an excluding selector applies to every other interface
+                Ok(s) if !s.is_name() => IfaceSet::from_iter([s]).contains(iface),
+                _ => false,
+            },
+        )
+        .map(|(_, rate)| *rate)
+        .min()
+        .unwrap_or(1)
+}
+
+// Match the name against a glob pattern of `*` and `?` wildcards
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the last star match one more byte
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sample_rate, IfaceSet, SampleRates};
+
+    #[test]
+    fn test_iface_set() {
+        let set = IfaceSet::parse(["veth*", "eth?", "!veth0", "/^br-[0-9a-f]+$/"]).unwrap();
+        assert!(set.contains("veth1a2b"));
+        assert!(set.contains("eth1"));
+        assert!(set.contains("br-1f2e"));
+        assert!(!set.contains("veth0"));
+        assert!(!set.contains("eth10"));
+        assert!(!set.contains("lo"));
+
+        // only excluding selectors match every other interface
+        let set = IfaceSet::parse(["!lo"]).unwrap();
+        assert!(set.contains("eth0"));
+        assert!(!set.contains("lo"));
+        assert!(!IfaceSet::default().contains("eth0"));
+
+        let set = IfaceSet::parse(["eth0", "veth*"]).unwrap();
+        assert_eq!(set.names().collect::<Vec<_>>(), vec!["eth0"]);
+        assert!(IfaceSet::parse(["/veth[/"]).is_err());
+        assert!(IfaceSet::parse(["!"]).is_err());
+    }
+
+    #[test]
+    fn test_iface_sample_rate() {
+        let rates_of = |entries: &[(&str, &str, u32)]| -> SampleRates {
+            entries
+                .iter()
+                .map(|(netns, iface, rate)| ((netns.to_string(), iface.to_string()), *rate))
+                .collect()
+        };
+
+        let rates = rates_of(&[("", "veth*", 10), ("", "eth0", 100)]);
+        assert_eq!(sample_rate(&rates, "", "eth0"), 100);
+        assert_eq!(sample_rate(&rates, "", "veth12"), 10);
+        assert_eq!(sample_rate(&rates, "", "lo"), 1);
+
+        let rates = rates_of(&[("", "!lo", 10)]);
+        assert_eq!(sample_rate(&rates, "", "lo"), 1);
+        assert_eq!(sample_rate(&rates, "", "eth0"), 10);
+
+        // the rates only apply to the interfaces of their namespace
+        let rates = rates_of(&[("", "eth0", 100), ("web", "veth*", 10)]);
+        assert_eq!(sample_rate(&rates, "web", "eth0"), 1);
+        assert_eq!(sample_rate(&rates, "web", "veth0"), 10);
+        assert_eq!(sample_rate(&rates, "", "veth0"), 1);
+    }
+}
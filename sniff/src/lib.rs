@@ -1,12 +1,28 @@
 pub mod app;
+pub mod capture;
 pub mod cidr;
 pub mod cmd;
 pub mod collector;
 pub mod config;
+pub mod diagnostic;
+pub mod dns;
 pub mod ebpf;
+pub mod expr;
 pub mod filter;
+pub mod flow;
+pub mod http;
+pub mod iface;
 pub mod metrics;
+pub mod netlink;
+pub mod netns;
 pub mod network;
+pub mod output;
+pub mod port;
+pub mod prefilter;
+pub mod reload;
+pub mod tcp;
+pub mod template;
+pub mod tls;
 
 pub mod util {
     use std::{
@@ -15,7 +31,9 @@ pub mod util {
         io,
     };
 
-    use anyhow::{anyhow, Result};
+    use log::error;
+
+    use crate::netlink;
 
     pub fn uname() -> io::Result<SysInfo> {
         let mut buf = unsafe { std::mem::zeroed() };
@@ -48,34 +66,15 @@ pub mod util {
         unsafe { CStr::from_ptr(buf.as_ptr()) }
     }
 
-    /// lookup whether the given network interface exists on the current host.
-    pub fn lookup_interface(iface_set: HashSet<&str>) -> Result<()> {
-        let mut result = HashSet::new();
-        unsafe {
-            let mut iface_addr: *mut libc::ifaddrs = std::ptr::null_mut();
-            if libc::getifaddrs(&mut iface_addr) == 0 {
-                let mut iface = iface_addr;
-                while !iface.is_null() {
-                    let ifa_name = (*iface).ifa_name;
-                    let ifa_addr = (*iface).ifa_addr;
-
-                    if !ifa_name.is_null() && !ifa_addr.is_null() {
-                        let c_interface = CStr::from_ptr(ifa_name);
-                        let interface = c_interface.to_str().unwrap();
-                        result.insert(interface);
-                    }
-                    iface = (*iface).ifa_next;
-                }
+    /// Returns the names of the network interfaces of the current network namespace,
+    /// see [crate::netlink::list_links]
+    pub fn local_interfaces() -> HashSet<String> {
+        match netlink::list_links() {
+            Ok(links) => links.into_iter().map(|link| link.name).collect(),
+            Err(e) => {
+                error!("failed to list the network interfaces by err: {}", e);
+                HashSet::new()
             }
         }
-        iface_set.iter().try_for_each(|&i| match result.get(i) {
-            Some(_) => Ok(()),
-            None => Err(anyhow!(
-                "'{}' network interface not exist in current machine",
-                i
-            )),
-        })?;
-
-        Ok(())
     }
 }
@@ -1,19 +1,23 @@
-use std::{collections::HashSet, str::FromStr, sync::Arc};
+use std::{collections::HashSet, path::Path, str::FromStr, sync::Arc, time::Duration};
 
 use clap::Parser;
-use ipnetwork::Ipv4Network;
 use log::{error, info};
 use netsniff::{
     app::Application,
     cidr::PrefixTree,
     cmd::{self, Cmd},
-    collector::{self, CollectorMap},
+    collector::CollectorMap,
     config::Traffic,
     ebpf,
     filter::Filter,
-    metrics,
+    iface::IfaceSet,
+    metrics, netlink,
+    netns::{self, Netns},
+    prefilter::Prefilter,
+    reload::{Reloader, RuleSet},
+    util,
 };
-use tokio::signal;
+use tokio::{signal, sync::mpsc};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -22,13 +26,40 @@ async fn main() -> anyhow::Result<()> {
 
     match command.sub_cmd {
         cmd::SubCmd::Check => {
+            // the selectors are checked against the current interfaces
             let ifaces = get_cmd_ifaces(&command);
-            for iface in ifaces.iter() {
-                ebpf::check_attach(iface.to_owned(), sniff_common::Flow::Ingress);
-                ebpf::check_attach(iface.to_owned(), sniff_common::Flow::Egress);
+            let netns = get_cmd_netns(&command);
+            let local_ifaces = match netns::enter(netns.as_ref(), util::local_interfaces) {
+                Ok(local_ifaces) => local_ifaces,
+                Err(e) => {
+                    error!("failed to enter the network namespace by err {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let mut targets: HashSet<String> = ifaces.names().map(str::to_owned).collect();
+            targets.extend(
+                local_ifaces
+                    .into_iter()
+                    .filter(|iface| ifaces.contains(iface)),
+            );
+            for iface in targets.iter() {
+                ebpf::check_attach(
+                    iface.to_owned(),
+                    netns.as_ref(),
+                    sniff_common::Flow::Ingress,
+                );
+                ebpf::check_attach(iface.to_owned(), netns.as_ref(), sniff_common::Flow::Egress);
             }
             return Ok(());
         }
+        cmd::SubCmd::Validate(validate) => {
+            validate_config(&validate);
+            return Ok(());
+        }
+        cmd::SubCmd::Interfaces => {
+            list_interfaces(&command);
+            return Ok(());
+        }
         cmd::SubCmd::Run(run) => {
             info!("read configuration from a config file");
             match Traffic::load_config_path(&run.config) {
@@ -36,68 +67,36 @@ async fn main() -> anyhow::Result<()> {
                     config.check()?;
 
                     // build metrics for data package export
-                    if let Err(e) = metrics::build_metrics(config.const_labels()) {
+                    if let Err(e) =
+                        metrics::build_metrics(config.const_labels(), config.packet_size_buckets())
+                    {
                         error!("failed to build metrics by err {}", e);
                     }
                     let export_internal = humantime::parse_duration(&config.export_interval)?;
 
-                    if let Some(rule) = config.rules {
-                        let mut trie = PrefixTree::<Arc<Box<Filter>>>::new();
-                        let mut ifaces: HashSet<String> = HashSet::new();
-                        let mut flow = 0x3;
-                        let mut proto: i32 = 0x3;
-                        let mut empty_filter: Vec<Arc<Box<Filter>>> = Vec::new();
-                        let mut collector_map = CollectorMap::new(export_internal);
-
-                        for item in rule {
-                            proto &= item.protocol as i32;
-                            flow &= item.bind_flow() as i32;
-
-                            // only get the intersection of the network interfaces
-                            let cidrs = item.cidrs.clone();
-                            let filter_item: Filter = item.into();
-
-                            ifaces.extend(filter_item.in_iface_filter.clone());
-                            ifaces.extend(filter_item.out_iface_filter.clone());
-
-                            for identity in collector::filter_to_identity(&filter_item) {
-                                info!("build metrics identity: [{}]", identity);
-                                collector_map.insert(
-                                    identity,
-                                    if !filter_item.label_values.is_empty() {
-                                        Some(filter_item.label_values.clone())
-                                    } else {
-                                        None
-                                    },
-                                );
-                            }
-
-                            let filter: Arc<Box<Filter>> = Arc::new(Box::new(filter_item));
-                            match cidrs {
-                                Some(cidrs) if !cidrs.is_empty() => {
-                                    /* handler non-empty filters */
-                                    for cidr in cidrs {
-                                        trie.insert(
-                                            Ipv4Network::from_str(cidr.as_ref())?,
-                                            filter.clone(),
-                                        );
-                                    }
-                                }
-                                _ => {
-                                    /* handler empty filters */
-                                    empty_filter.push(filter);
-                                }
-                            }
-                        }
-
-                        let mut application = Application::new(
-                            ifaces.into_iter().collect(),
-                            trie,
-                            Some(empty_filter),
-                            Some(collector_map),
-                        );
-                        tokio::spawn(async move { application.run(proto, flow.into()).await });
+                    let rules = RuleSet::build(&config)?;
+                    let mut collector_map = CollectorMap::new(export_internal);
+                    for (identity, label_values) in rules.identities {
+                        info!("build metrics identity: [{}]", identity);
+                        collector_map.insert(identity, label_values);
                     }
+
+                    // rules are reloaded on SIGHUP and when the config file is modified
+                    let (reload_tx, reload_rx) = mpsc::channel(1);
+                    let reloader = Reloader::new(&run.config, &config);
+                    tokio::spawn(async move { reloader.watch(reload_tx).await });
+
+                    let mut application = Application::new(
+                        rules.ifaces,
+                        rules.trie,
+                        Some(rules.empty_filter),
+                        Some(collector_map),
+                    )
+                    .with_match_policy(rules.policy)
+                    .with_sampling(rules.sample_rates, rules.sample_flow_hash)
+                    .with_reload(rules.items, reload_rx);
+                    let (prefilter, flow) = (rules.prefilter, rules.flow);
+                    tokio::spawn(async move { application.run(prefilter, flow).await });
                 }
                 Err(e) => {
                     error!("failed to load config '{}' by err {}", &run.config, e);
@@ -105,24 +104,42 @@ async fn main() -> anyhow::Result<()> {
                 }
             }
         }
-        cmd::SubCmd::Tcp | cmd::SubCmd::Udp | cmd::SubCmd::All => {
-            // TODO: handler empty filter case
+        cmd::SubCmd::Tcp(_) | cmd::SubCmd::Udp(_) | cmd::SubCmd::All(_) => {
             info!("read configuration from a command flag");
             let ifaces = get_cmd_ifaces(&command);
+            let netns = get_cmd_netns(&command);
             let proto = command.sub_cmd.proto_num();
-            let trie = if !command.cidrs.is_empty() {
+            let mut pass_filter = Filter::default_pass_filter()
+                .with_tls_ports(&command.tls_ports)
+                .with_http_ports(&command.http_ports);
+            pass_filter.protocol = proto.into();
+            if let Some(expression) = command.sub_cmd.expression() {
+                match expression.parse() {
+                    Ok(expr) => pass_filter = pass_filter.with_matcher(expr),
+                    Err(e) => {
+                        error!("failed to parse filter expression by err {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            for cidr in command.cidrs.iter() {
+                match ipnetwork::Ipv4Network::from_str(cidr) {
+                    Ok(addr) => pass_filter.cidrs.push(addr),
+                    Err(e) => {
+                        error!("failed to parse {} cidr to ipv4Network by err {}", cidr, e);
+                        std::process::exit(1);
+                    }
+                };
+            }
+            let prefilter = Prefilter::compile([&pass_filter]);
+            let pass_filter = Arc::new(Box::new(pass_filter));
+
+            // without cidrs, the pass filter is evaluated for every packet
+            let empty_filter = command.cidrs.is_empty().then(|| vec![pass_filter.clone()]);
+            let trie = if !pass_filter.cidrs.is_empty() {
                 let mut trie = PrefixTree::new();
-                let empty_filter = Arc::new(Box::new(Filter::default_pass_filter()));
-                for cidr in command.cidrs.iter() {
-                    match ipnetwork::Ipv4Network::from_str(cidr) {
-                        Ok(addr) => {
-                            trie.insert(addr, empty_filter.clone());
-                        }
-                        Err(e) => {
-                            error!("failed to parse {} cidr to ipv4Network by err {}", cidr, e);
-                            std::process::exit(1);
-                        }
-                    };
+                for cidr in pass_filter.cidrs.iter() {
+                    trie.insert(*cidr, pass_filter.clone());
                 }
 
                 trie
@@ -139,8 +156,28 @@ async fn main() -> anyhow::Result<()> {
                 cmd::Flow::All => sniff_common::Flow::All,
             };
 
-            let mut application = Application::new(ifaces.into_iter().collect(), trie, None, None);
-            tokio::spawn(async move { application.run(proto, flow).await });
+            let limits = command.sub_cmd.limits();
+            let mut application = Application::new(vec![(netns, ifaces)], trie, empty_filter, None)
+                .with_output(command.output)
+                .with_limits(limits);
+
+            // the capture stops on SIGINT, after the duration or once a limit is reached
+            tokio::select! {
+                _ = application.run(prefilter, flow) => info!("the capture limit is reached"),
+                _ = sleep(limits.duration) => info!("the capture duration is reached"),
+                ret = signal::ctrl_c() => {
+                    if let Err(e) = ret {
+                        error!("failed to listen SIGINT signal by err: {}", e);
+                    }
+                }
+            }
+            application.detach();
+            if let Some(summary) = application.summary() {
+                eprint!("{}", summary);
+            }
+            info!("Sniff program exits normally and detaches the eBPF program");
+
+            return Ok(());
         }
     };
 
@@ -152,27 +189,121 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Sleep for the duration, or forever without one
+async fn sleep(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
 fn setup(command: &Cmd) {
     env_logger::Builder::from_env(env_logger::Env::new().default_filter_or(&command.verbose))
         .format_module_path(false)
         .init();
 
+    // validating a configuration file or listing the interfaces does not attach any program
+    if matches!(
+        command.sub_cmd,
+        cmd::SubCmd::Validate(_) | cmd::SubCmd::Interfaces
+    ) {
+        return;
+    }
     if unsafe { libc::geteuid() } != 0 {
         error!("Sniff program must be run as root!");
         std::process::exit(1);
     }
 }
 
-fn get_cmd_ifaces(command: &Cmd) -> HashSet<String> {
-    let mut ifaces = HashSet::new();
+/// Report every error of the configuration file, exits with 1 if any
+fn validate_config(validate: &cmd::Validate) {
+    let path = &validate.config;
+    let report = Traffic::read(Path::new(path), !validate.skip_iface_check);
+    for w in report.warnings.iter() {
+        eprintln!("{}", w);
+    }
+    for e in report.errors.iter() {
+        eprintln!("{}", e);
+    }
+    if !report.errors.is_empty() {
+        eprintln!("{}: {} error(s) found", path, report.errors.len());
+        std::process::exit(1);
+    }
+    for item in report.traffic.iter().flat_map(|t| t.rules.iter().flatten()) {
+        let source = item.source.as_deref().unwrap_or(path);
+        println!("{}: rule '{}'", source, item.name);
+    }
+    println!("{}: configuration is valid", path);
+}
+
+/// Print the network interfaces, only those matching the `-i` selectors if any
+fn list_interfaces(command: &Cmd) {
+    let netns = get_cmd_netns(command);
+    let selectors = (!command.ifaces.is_empty()).then(|| get_cmd_ifaces(command));
+    let mut links = match netns::enter(netns.as_ref(), netlink::list_links).and_then(|l| l) {
+        Ok(links) => links,
+        Err(e) => {
+            error!("failed to list the network interfaces by err {}", e);
+            std::process::exit(1);
+        }
+    };
+    links.retain(|link| selectors.as_ref().is_none_or(|s| s.contains(&link.name)));
+    links.sort_by_key(|link| link.index);
+
+    let width = links.iter().map(|link| link.name.len()).max().unwrap_or(0);
+    println!(
+        "{:<7}{:<width$}  {:<13}{:<7}{:<16}ATTACH",
+        "INDEX",
+        "NAME",
+        "TYPE",
+        "MTU",
+        "STATE",
+        width = width.max(4)
+    );
+    for link in links {
+        println!(
+            "{:<7}{:<width$}  {:<13}{:<7}{:<16}{}",
+            link.index,
+            link.name,
+            link.kind,
+            link.mtu,
+            link.state,
+            if link.attachable() {
+                "yes"
+            } else {
+                "no (no Ethernet header)"
+            },
+            width = width.max(4)
+        );
+    }
+}
+
+fn get_cmd_netns(command: &Cmd) -> Option<Netns> {
+    let netns = command.netns.as_ref()?;
+    match netns.parse::<Netns>() {
+        Ok(netns) if netns.exists() => Some(netns),
+        Ok(_) => {
+            error!("the '{}' network namespace does not exist", netns);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("failed to parse network namespace by err {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn get_cmd_ifaces(command: &Cmd) -> IfaceSet {
     if command.ifaces.is_empty() {
         error!("must specify at least one network interface to which Sniff is attached.");
         std::process::exit(1);
     }
 
-    for iface in &command.ifaces {
-        ifaces.insert(iface.to_owned());
+    match IfaceSet::parse(&command.ifaces) {
+        Ok(ifaces) => ifaces,
+        Err(e) => {
+            error!("failed to parse network interface by err {}", e);
+            std::process::exit(1);
+        }
     }
-
-    ifaces
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Result;
 use axum::{
@@ -8,16 +8,41 @@ use axum::{
     routing, Router,
 };
 use log::{error, info};
-use prometheus::{IntGaugeVec, Opts, TextEncoder};
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, TextEncoder,
+};
+use sniff_common::Flow;
 use tokio::net::TcpListener;
 
+use crate::flow::TcpStates;
+
 static mut PACKET_TOL: Option<Box<IntGaugeVec>> = None;
+static mut PACKET_SIZE: Option<Box<HistogramVec>> = None;
+static mut DNS_QUERIES: Option<Box<IntCounterVec>> = None;
+static mut DNS_LATENCY: Option<Box<HistogramVec>> = None;
+static mut TLS_BYTES: Option<Box<IntCounterVec>> = None;
+static mut TLS_TRUNCATED: Option<Box<IntCounterVec>> = None;
+static mut HTTP_REQUESTS: Option<Box<IntCounterVec>> = None;
+static mut HTTP_RESPONSES: Option<Box<IntCounterVec>> = None;
+static mut TCP_EVENTS: Option<Box<IntCounterVec>> = None;
+static mut TCP_CONNECTIONS: Option<Box<IntGaugeVec>> = None;
+static mut TCP_HANDSHAKE_RTT: Option<Box<HistogramVec>> = None;
+static mut TCP_RETRANSMITS: Option<Box<IntCounterVec>> = None;
+static mut ATTACHED_IFACES: Option<Box<IntGaugeVec>> = None;
 
-pub const PACKET_TOL_LV_CAP: usize = 5;
+pub const PACKET_TOL_LV_CAP: usize = 7;
 
 #[allow(static_mut_refs)]
-pub fn build_metrics(const_lables: Vec<String>) -> Result<()> {
-    let mut lable_names = vec!["rule_name", "traffic", "protocol", "network_iface", "port"];
+pub fn build_metrics(const_lables: Vec<String>, size_buckets: Option<Vec<f64>>) -> Result<()> {
+    let mut lable_names = vec![
+        "rule_name",
+        "traffic",
+        "protocol",
+        "network_iface",
+        "port",
+        "sample_rate",
+        "netns",
+    ];
     const_lables.iter().for_each(|v| {
         lable_names.push(v);
     });
@@ -35,6 +60,58 @@ pub fn build_metrics(const_lables: Vec<String>) -> Result<()> {
         PACKET_TOL = Some(gauge);
     };
     info!(r"success to build metrics instance: 'network_packet_tolal'");
+
+    if let Some(buckets) = size_buckets {
+        let histogram = Box::new(HistogramVec::new(
+            HistogramOpts::new(
+                "network_packet_size_bytes",
+                "record the size distribution of incoming and outgoing network packets",
+            )
+            .buckets(buckets),
+            &lable_names,
+        )?);
+
+        prometheus::register(histogram.clone())?;
+        unsafe {
+            PACKET_SIZE = Some(histogram);
+        };
+        info!(r"success to build metrics instance: 'network_packet_size_bytes'");
+    }
+
+    build_dns_metrics()?;
+    build_tls_metrics()?;
+    build_http_metrics()?;
+    build_tcp_metrics()?;
+    build_iface_metrics()
+}
+
+#[allow(static_mut_refs)]
+fn build_dns_metrics() -> Result<()> {
+    let queries = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_dns_queries_total",
+            "record the number of answered dns queries",
+        ),
+        &["rule_name", "network_iface", "netns", "rcode", "qtype"],
+    )?);
+    let latency = Box::new(HistogramVec::new(
+        HistogramOpts::new(
+            "netsniff_dns_latency_seconds",
+            "record the latency between a dns query and its response",
+        )
+        .buckets(vec![
+            0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+        ]),
+        &["rule_name", "network_iface", "netns", "qtype"],
+    )?);
+
+    prometheus::register(queries.clone())?;
+    prometheus::register(latency.clone())?;
+    unsafe {
+        DNS_QUERIES = Some(queries);
+        DNS_LATENCY = Some(latency);
+    };
+    info!(r"success to build metrics instance: 'netsniff_dns_queries_total'");
     Ok(())
 }
 
@@ -51,6 +128,326 @@ pub fn set_gauge(val: i64, label_values: &HashMap<&str, &str>) {
     gauge.with(label_values).set(val);
 }
 
+#[allow(static_mut_refs)]
+fn build_tls_metrics() -> Result<()> {
+    let bytes = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_tls_bytes_total",
+            "record the size of network packets by the TLS server name of their flow",
+        ),
+        &[
+            "rule_name",
+            "traffic",
+            "network_iface",
+            "netns",
+            "server_name",
+        ],
+    )?);
+
+    let truncated = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_tls_truncated_client_hellos_total",
+            "record the number of TLS ClientHello messages whose extensions were cut off",
+        ),
+        &["rule_name", "network_iface", "netns"],
+    )?);
+
+    prometheus::register(bytes.clone())?;
+    prometheus::register(truncated.clone())?;
+    unsafe {
+        TLS_BYTES = Some(bytes);
+        TLS_TRUNCATED = Some(truncated);
+    };
+    info!(r"success to build metrics instance: 'netsniff_tls_bytes_total'");
+    Ok(())
+}
+
+#[allow(static_mut_refs)]
+fn build_http_metrics() -> Result<()> {
+    let requests = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_http_requests_total",
+            "record the number of plain-text HTTP requests",
+        ),
+        &["rule_name", "network_iface", "netns", "method"],
+    )?);
+    let responses = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_http_responses_total",
+            "record the number of plain-text HTTP responses by status class",
+        ),
+        &["rule_name", "network_iface", "netns", "status_class"],
+    )?);
+
+    prometheus::register(requests.clone())?;
+    prometheus::register(responses.clone())?;
+    unsafe {
+        HTTP_REQUESTS = Some(requests);
+        HTTP_RESPONSES = Some(responses);
+    };
+    info!(r"success to build metrics instance: 'netsniff_http_requests_total'");
+    Ok(())
+}
+
+#[allow(static_mut_refs)]
+fn build_tcp_metrics() -> Result<()> {
+    let events = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_tcp_events_total",
+            "record the TCP connection lifecycle events (syn, rst, half_open...)",
+        ),
+        &["rule_name", "network_iface", "netns", "event"],
+    )?);
+    let connections = Box::new(IntGaugeVec::new(
+        Opts::new(
+            "netsniff_tcp_connections",
+            "record the number of tracked TCP connections by state",
+        ),
+        &["rule_name", "network_iface", "netns", "state"],
+    )?);
+
+    let handshake_rtt = Box::new(HistogramVec::new(
+        HistogramOpts::new(
+            "netsniff_tcp_handshake_rtt_seconds",
+            "record the time between the SYN and the ACK completing the TCP handshake",
+        )
+        .buckets(vec![
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+        ]),
+        &["rule_name", "peer_cidr"],
+    )?);
+    let retransmits = Box::new(IntCounterVec::new(
+        Opts::new(
+            "netsniff_tcp_retransmits_total",
+            "record the number of retransmitted TCP segments",
+        ),
+        &["rule_name", "peer_cidr"],
+    )?);
+
+    prometheus::register(events.clone())?;
+    prometheus::register(connections.clone())?;
+    prometheus::register(handshake_rtt.clone())?;
+    prometheus::register(retransmits.clone())?;
+    unsafe {
+        TCP_EVENTS = Some(events);
+        TCP_CONNECTIONS = Some(connections);
+        TCP_HANDSHAKE_RTT = Some(handshake_rtt);
+        TCP_RETRANSMITS = Some(retransmits);
+    };
+    info!(r"success to build metrics instance: 'netsniff_tcp_events_total'");
+    Ok(())
+}
+
+#[allow(static_mut_refs)]
+fn build_iface_metrics() -> Result<()> {
+    let attached = Box::new(IntGaugeVec::new(
+        Opts::new(
+            "netsniff_attached_interfaces",
+            "record the network interfaces the eBPF programs are currently attached to",
+        ),
+        &["network_iface", "traffic", "netns"],
+    )?);
+
+    prometheus::register(attached.clone())?;
+    unsafe {
+        ATTACHED_IFACES = Some(attached);
+    };
+    info!(r"success to build metrics instance: 'netsniff_attached_interfaces'");
+    Ok(())
+}
+
+/// Mark the eBPF program of the flow as attached to or detached from the network interface
+#[allow(static_mut_refs)]
+pub fn set_attached_iface(iface: &str, netns: &str, flow: &Flow, attached: bool) {
+    let gauge = unsafe {
+        if ATTACHED_IFACES.is_none() {
+            error!("netsniff_attached_interfaces metrics have not been initialized");
+            return;
+        }
+
+        ATTACHED_IFACES.as_ref().unwrap()
+    };
+    let traffic = match flow {
+        Flow::Ingress => "ingress",
+        _ => "egress",
+    };
+    if attached {
+        gauge.with_label_values(&[iface, traffic, netns]).set(1);
+    } else {
+        // the interface may have been attached before the collector
+        let _ = gauge.remove_label_values(&[iface, traffic, netns]);
+    }
+}
+
+#[allow(static_mut_refs)]
+pub fn inc_tcp_event(label_values: &[&str], n: u64) {
+    let counter = unsafe {
+        if TCP_EVENTS.is_none() {
+            error!("netsniff_tcp_events_total metrics have not been initialized");
+            return;
+        }
+
+        TCP_EVENTS.as_ref().unwrap()
+    };
+    counter.with_label_values(label_values).inc_by(n);
+}
+
+#[allow(static_mut_refs)]
+pub fn observe_tcp_handshake_rtt(label_values: &[&str], rtt: Duration) {
+    let histogram = unsafe {
+        if TCP_HANDSHAKE_RTT.is_none() {
+            error!("netsniff_tcp_handshake_rtt_seconds metrics have not been initialized");
+            return;
+        }
+
+        TCP_HANDSHAKE_RTT.as_ref().unwrap()
+    };
+    histogram
+        .with_label_values(label_values)
+        .observe(rtt.as_secs_f64());
+}
+
+#[allow(static_mut_refs)]
+pub fn inc_tcp_retransmit(label_values: &[&str], n: u64) {
+    let counter = unsafe {
+        if TCP_RETRANSMITS.is_none() {
+            error!("netsniff_tcp_retransmits_total metrics have not been initialized");
+            return;
+        }
+
+        TCP_RETRANSMITS.as_ref().unwrap()
+    };
+    counter.with_label_values(label_values).inc_by(n);
+}
+
+#[allow(static_mut_refs)]
+pub fn set_tcp_connections(states: &TcpStates) {
+    let gauge = unsafe {
+        if TCP_CONNECTIONS.is_none() {
+            error!("netsniff_tcp_connections metrics have not been initialized");
+            return;
+        }
+
+        TCP_CONNECTIONS.as_ref().unwrap()
+    };
+    // states without connections left are dropped
+    gauge.reset();
+    for ((rule_name, iface, netns, state), count) in states {
+        gauge
+            .with_label_values(&[rule_name, iface, netns, state.as_str()])
+            .set(*count);
+    }
+}
+
+#[allow(static_mut_refs)]
+pub fn inc_http_request(label_values: &[&str], n: u64) {
+    let counter = unsafe {
+        if HTTP_REQUESTS.is_none() {
+            error!("netsniff_http_requests_total metrics have not been initialized");
+            return;
+        }
+
+        HTTP_REQUESTS.as_ref().unwrap()
+    };
+    counter.with_label_values(label_values).inc_by(n);
+}
+
+#[allow(static_mut_refs)]
+pub fn inc_http_response(label_values: &[&str], n: u64) {
+    let counter = unsafe {
+        if HTTP_RESPONSES.is_none() {
+            error!("netsniff_http_responses_total metrics have not been initialized");
+            return;
+        }
+
+        HTTP_RESPONSES.as_ref().unwrap()
+    };
+    counter.with_label_values(label_values).inc_by(n);
+}
+
+#[allow(static_mut_refs)]
+pub fn add_tls_bytes(label_values: &[&str], bytes: u64) {
+    let counter = unsafe {
+        if TLS_BYTES.is_none() {
+            error!("netsniff_tls_bytes_total metrics have not been initialized");
+            return;
+        }
+
+        TLS_BYTES.as_ref().unwrap()
+    };
+    counter.with_label_values(label_values).inc_by(bytes);
+}
+
+#[allow(static_mut_refs)]
+pub fn inc_tls_truncated(label_values: &[&str], n: u64) {
+    let counter = unsafe {
+        if TLS_TRUNCATED.is_none() {
+            error!("netsniff_tls_truncated_client_hellos_total metrics have not been initialized");
+            return;
+        }
+
+        TLS_TRUNCATED.as_ref().unwrap()
+    };
+    counter.with_label_values(label_values).inc_by(n);
+}
+
+/// Count an answered query of the rule, network interface and network namespace `series`
+#[allow(static_mut_refs)]
+pub fn inc_dns_query(
+    series: [&str; 3],
+    rcode: &str,
+    qtype: &str,
+    latency: Option<Duration>,
+    n: u64,
+) {
+    let [rule_name, iface, netns] = series;
+    let (queries, latency_hist) = unsafe {
+        if DNS_QUERIES.is_none() || DNS_LATENCY.is_none() {
+            error!("netsniff_dns_queries_total metrics have not been initialized");
+            return;
+        }
+
+        (DNS_QUERIES.as_ref().unwrap(), DNS_LATENCY.as_ref().unwrap())
+    };
+    queries
+        .with_label_values(&[rule_name, iface, netns, rcode, qtype])
+        .inc_by(n);
+    if let Some(latency) = latency {
+        latency_hist
+            .with_label_values(&[rule_name, iface, netns, qtype])
+            .observe(latency.as_secs_f64());
+    }
+}
+
+/// Returns the packet size histogram of the given label values, if enabled
+#[allow(static_mut_refs)]
+pub fn packet_size_histogram(label_values: &HashMap<&str, &str>) -> Option<Histogram> {
+    let histogram = unsafe { PACKET_SIZE.as_ref()? };
+    match histogram.get_metric_with(label_values) {
+        Ok(h) => Some(h),
+        Err(e) => {
+            error!(
+                "failed to get network_packet_size_bytes metrics by err {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Removes the packet series of the given label values, once their rule is removed or changed
+#[allow(static_mut_refs)]
+pub fn remove_packet_series(label_values: &HashMap<&str, &str>) {
+    unsafe {
+        if let Some(gauge) = PACKET_TOL.as_ref() {
+            let _ = gauge.remove(label_values);
+        }
+        if let Some(histogram) = PACKET_SIZE.as_ref() {
+            let _ = histogram.remove(label_values);
+        }
+    }
+}
+
 /// Sniff's metrics server has the following two functions:
 ///
 /// 1. Provide a health check endpoint to report that the service is normal(`/-/health`)
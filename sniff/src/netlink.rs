@@ -0,0 +1,511 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use log::{debug, error, warn};
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::mpsc,
+};
+
+use crate::netns::{self, Netns};
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFO_LEN: usize = 16;
+const RTA_HDR_LEN: usize = 4;
+
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_OPERSTATE: u16 = 16;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IF_OPER_UNKNOWN: u8 = 0;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ARPHRD_NONE: u16 = 65534;
+
+/// A change of the network interfaces of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkEvent {
+    Added(Link),
+    Removed(String),
+}
+
+/// A subscription to the network interfaces created and destroyed in a network namespace,
+/// by the `RTM_NEWLINK`/`RTM_DELLINK` messages of a rtnetlink socket.
+pub struct LinkWatcher {
+    fd: AsyncFd<OwnedFd>,
+    netns: Option<Netns>,
+}
+
+impl LinkWatcher {
+    /// Subscribe to the link changes of the namespace, or of the current namespace without one.
+    ///
+    /// The changes are buffered from now on, list the interfaces after subscribing so none is missed.
+    pub fn open(netns: Option<Netns>) -> io::Result<Self> {
+        // the socket stays in the namespace it was opened in
+        let socket = netns::enter(netns.as_ref(), link_socket)??;
+        let fd = AsyncFd::with_interest(socket, Interest::READABLE)?;
+
+        Ok(Self { fd, netns })
+    }
+
+    pub async fn watch(mut self, tx: mpsc::Sender<(Option<Netns>, LinkEvent)>) {
+        // the names by interface index, a renamed interface is removed then added
+        let mut links: HashMap<i32, String> = HashMap::new();
+        if let Ok(current) = self.list_links() {
+            resync(&mut links, current);
+        }
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let mut guard = match self.fd.ready_mut(Interest::READABLE).await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    error!("failed to poll the rtnetlink socket by err: {}", e);
+                    return;
+                }
+            };
+            let len = unsafe {
+                libc::recv(
+                    guard.get_inner().as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    guard.clear_ready();
+                    continue;
+                }
+                drop(guard);
+
+                // the socket buffer overflowed on a burst of changes, the lost messages
+                // are made up for by comparing with the current interfaces
+                if err.raw_os_error() == Some(libc::ENOBUFS) {
+                    warn!(
+                        "rtnetlink messages were lost, resync the network interfaces, netns: '{}'",
+                        netns::label(self.netns.as_ref())
+                    );
+                    match self.list_links() {
+                        Ok(current) => {
+                            let events = resync(&mut links, current);
+                            if !self.send(&tx, events).await {
+                                return;
+                            }
+                        }
+                        Err(e) => error!("failed to list the network interfaces by err: {}", e),
+                    }
+                    continue;
+                }
+                error!("failed to read the rtnetlink socket by err: {}", err);
+                return;
+            }
+
+            for (kind, link) in parse_links(&buf[..len as usize]) {
+                let events = match kind {
+                    RTM_NEWLINK => match links.insert(link.index, link.name.clone()) {
+                        Some(old) if old == link.name => continue,
+                        Some(old) => vec![LinkEvent::Removed(old), LinkEvent::Added(link)],
+                        None => vec![LinkEvent::Added(link)],
+                    },
+                    _ => {
+                        links.remove(&link.index);
+                        vec![LinkEvent::Removed(link.name)]
+                    }
+                };
+                if !self.send(&tx, events).await {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Send the events, returns false once the receiver is gone
+    async fn send(
+        &self,
+        tx: &mpsc::Sender<(Option<Netns>, LinkEvent)>,
+        events: Vec<LinkEvent>,
+    ) -> bool {
+        for event in events {
+            debug!(
+                "network interface event: {:?}, netns: '{}'",
+                event,
+                netns::label(self.netns.as_ref())
+            );
+            if tx.send((self.netns.clone(), event)).await.is_err() {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn list_links(&self) -> io::Result<Vec<Link>> {
+        netns::enter(self.netns.as_ref(), list_links)?
+    }
+}
+
+/// Update the names by interface index to the current interfaces,
+/// returning the changes as if their messages were received
+fn resync(links: &mut HashMap<i32, String>, current: Vec<Link>) -> Vec<LinkEvent> {
+    let current: HashMap<i32, Link> = current.into_iter().map(|l| (l.index, l)).collect();
+    let mut events = Vec::new();
+    links.retain(|index, name| {
+        let kept = current.get(index).is_some_and(|link| link.name == *name);
+        if !kept {
+            events.push(LinkEvent::Removed(name.to_owned()));
+        }
+        kept
+    });
+    for (index, link) in current {
+        if let Entry::Vacant(entry) = links.entry(index) {
+            entry.insert(link.name.clone());
+            events.push(LinkEvent::Added(link));
+        }
+    }
+
+    events
+}
+
+/// A network interface listed by `RTM_GETLINK`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub index: i32,
+    pub name: String,
+    /// the link kind (`veth`, `bridge`...), or the hardware type of a link without one (`ether`, `loopback`...)
+    pub kind: String,
+    pub mtu: u32,
+    /// the operational state (`up`, `down`, `unknown`...)
+    pub state: &'static str,
+    /// the `ARPHRD_*` hardware type
+    hw_type: u16,
+}
+
+impl Link {
+    /// Whether the eBPF program can parse the packets of the interface, it expects an Ethernet header
+    pub fn attachable(&self) -> bool {
+        matches!(self.hw_type, ARPHRD_ETHER | ARPHRD_LOOPBACK)
+    }
+}
+
+/// List every network interface of the current network namespace, including
+/// the interfaces without an address such as bridge ports.
+pub fn list_links() -> io::Result<Vec<Link>> {
+    let socket = route_socket(0, 0)?;
+
+    // a dump request of the links of every address family
+    let mut req = Vec::with_capacity(NLMSG_HDR_LEN + IFINFO_LEN);
+    req.extend(((NLMSG_HDR_LEN + IFINFO_LEN) as u32).to_ne_bytes());
+    req.extend(RTM_GETLINK.to_ne_bytes());
+    req.extend((NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    req.extend(1u32.to_ne_bytes());
+    req.extend(0u32.to_ne_bytes());
+    req.extend([0u8; IFINFO_LEN]);
+    let sent = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            req.as_ptr() as *const libc::c_void,
+            req.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut links = Vec::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let len = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (kind, msg) in messages(&buf[..len as usize]) {
+            match kind {
+                NLMSG_DONE => return Ok(links),
+                NLMSG_ERROR => {
+                    let code = msg
+                        .get(..4)
+                        .map(|b| i32::from_ne_bytes(b.try_into().unwrap()))
+                        .unwrap_or_default();
+                    return Err(io::Error::from_raw_os_error(-code));
+                }
+                RTM_NEWLINK => links.extend(parse_link(msg)),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn link_socket() -> io::Result<OwnedFd> {
+    route_socket(libc::SOCK_NONBLOCK, libc::RTMGRP_LINK as u32)
+}
+
+fn route_socket(flags: i32, groups: u32) -> io::Result<OwnedFd> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | flags,
+            libc::NETLINK_ROUTE,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = groups;
+        let ret = libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as u32,
+        );
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Returns the (message type, link) of the link messages
+fn parse_links(buf: &[u8]) -> Vec<(u16, Link)> {
+    messages(buf)
+        .into_iter()
+        .filter(|(kind, _)| matches!(*kind, RTM_NEWLINK | RTM_DELLINK))
+        .filter_map(|(kind, msg)| parse_link(msg).map(|link| (kind, link)))
+        .collect()
+}
+
+/// Returns the (message type, payload) of the netlink messages
+fn messages(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut messages = Vec::new();
+    let mut offset = 0;
+    while offset + NLMSG_HDR_LEN <= buf.len() {
+        let msg_len = u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+        let msg_type = u16::from_ne_bytes(buf[offset + 4..offset + 6].try_into().unwrap());
+        if msg_len < NLMSG_HDR_LEN || offset + msg_len > buf.len() {
+            break;
+        }
+
+        messages.push((msg_type, &buf[offset + NLMSG_HDR_LEN..offset + msg_len]));
+        offset += align(msg_len);
+    }
+
+    messages
+}
+
+// Parse the `ifinfomsg` and the attributes of a link message, a link without name is skipped
+fn parse_link(msg: &[u8]) -> Option<Link> {
+    if msg.len() < IFINFO_LEN {
+        return None;
+    }
+    let hw_type = u16::from_ne_bytes(msg[2..4].try_into().unwrap());
+    let index = i32::from_ne_bytes(msg[4..8].try_into().unwrap());
+
+    let (mut name, mut kind, mut mtu, mut state) = (None, None, 0, IF_OPER_UNKNOWN);
+    for (attr, value) in attributes(&msg[IFINFO_LEN..]) {
+        match attr {
+            IFLA_IFNAME => name = Some(c_string(value)),
+            IFLA_MTU if value.len() >= 4 => {
+                mtu = u32::from_ne_bytes(value[..4].try_into().unwrap())
+            }
+            IFLA_OPERSTATE if !value.is_empty() => state = value[0],
+            IFLA_LINKINFO => {
+                kind = attributes(value)
+                    .find(|(attr, _)| *attr == IFLA_INFO_KIND)
+                    .map(|(_, value)| c_string(value));
+            }
+            _ => {}
+        }
+    }
+
+    Some(Link {
+        index,
+        name: name?,
+        kind: kind.unwrap_or_else(|| hw_type_name(hw_type)),
+        mtu,
+        state: oper_state_name(state),
+        hw_type,
+    })
+}
+
+// Iterate the (type, value) of the route attributes
+fn attributes(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < RTA_HDR_LEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        // the nested flag is not part of the type
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & 0x3fff;
+        if len < RTA_HDR_LEN || len > buf.len() {
+            return None;
+        }
+
+        let value = &buf[RTA_HDR_LEN..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind, value))
+    })
+}
+
+fn c_string(value: &[u8]) -> String {
+    let value = value.split(|b| *b == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(value).to_string()
+}
+
+fn hw_type_name(hw_type: u16) -> String {
+    match hw_type {
+        ARPHRD_ETHER => "ether".to_string(),
+        ARPHRD_LOOPBACK => "loopback".to_string(),
+        ARPHRD_NONE => "none".to_string(),
+        _ => format!("arphrd-{}", hw_type),
+    }
+}
+
+// The `IF_OPER_*` states of RFC 2863
+fn oper_state_name(state: u8) -> &'static str {
+    match state {
+        1 => "notpresent",
+        2 => "down",
+        3 => "lowerlayerdown",
+        4 => "testing",
+        5 => "dormant",
+        6 => "up",
+        _ => "unknown",
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{messages, parse_link, parse_links, resync, LinkEvent, RTM_DELLINK, RTM_NEWLINK};
+
+    fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut attr = Vec::new();
+        attr.extend(((4 + value.len()) as u16).to_ne_bytes());
+        attr.extend(kind.to_ne_bytes());
+        attr.extend(value);
+        attr.resize((attr.len() + 3) & !3, 0);
+        attr
+    }
+
+    fn link_message(kind: u16, index: i32, hw_type: u16, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let attrs = attrs.concat();
+        let mut msg = Vec::new();
+        msg.extend(((16 + 16 + attrs.len()) as u32).to_ne_bytes());
+        msg.extend(kind.to_ne_bytes());
+        msg.extend([0u8; 10]);
+        msg.extend([0u8; 2]);
+        msg.extend(hw_type.to_ne_bytes());
+        msg.extend(index.to_ne_bytes());
+        msg.extend([0u8; 8]);
+        msg.extend(attrs);
+        msg
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        attribute(3, format!("{}\0", name).as_bytes())
+    }
+
+    #[test]
+    fn test_parse_links() {
+        let mut buf = link_message(RTM_NEWLINK, 7, 1, &[name("veth1a2b")]);
+        buf.extend(link_message(RTM_DELLINK, 3, 1, &[name("eth1")]));
+        buf.extend(link_message(24, 1, 772, &[name("lo")]));
+
+        let links: Vec<_> = parse_links(&buf)
+            .into_iter()
+            .map(|(kind, link)| (kind, link.index, link.name))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (RTM_NEWLINK, 7, "veth1a2b".to_string()),
+                (RTM_DELLINK, 3, "eth1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_link() {
+        let link_info = attribute(1, b"veth\0");
+        let buf = link_message(
+            RTM_NEWLINK,
+            7,
+            1,
+            &[
+                name("veth1a2b"),
+                attribute(4, &1500u32.to_ne_bytes()),
+                attribute(16, &[6]),
+                attribute(18 | 0x8000, &link_info),
+            ],
+        );
+        let link = parse_link(messages(&buf)[0].1).unwrap();
+        assert_eq!((link.index, link.name.as_str()), (7, "veth1a2b"));
+        assert_eq!(
+            (link.kind.as_str(), link.mtu, link.state),
+            ("veth", 1500, "up")
+        );
+        assert!(link.attachable());
+
+        // a tunnel without an Ethernet header
+        let buf = link_message(RTM_NEWLINK, 9, 65534, &[name("wg0")]);
+        let link = parse_link(messages(&buf)[0].1).unwrap();
+        assert_eq!((link.kind.as_str(), link.state), ("none", "unknown"));
+        assert!(!link.attachable());
+    }
+
+    #[test]
+    fn test_resync_links() {
+        let link = |index: i32, name: &str| {
+            let buf = link_message(RTM_NEWLINK, index, 1, &[self::name(name)]);
+            parse_link(messages(&buf)[0].1).unwrap()
+        };
+        let mut links = HashMap::from([
+            (1, "lo".to_string()),
+            (7, "veth1a2b".to_string()),
+            (8, "veth3c4d".to_string()),
+        ]);
+
+        // veth1a2b was destroyed, veth3c4d renamed and veth5e6f created while messages were lost
+        let events = resync(
+            &mut links,
+            vec![link(1, "lo"), link(8, "eth1"), link(9, "veth5e6f")],
+        );
+        assert_eq!(events.len(), 4);
+        assert!(events.contains(&LinkEvent::Removed("veth1a2b".to_string())));
+        assert!(events.contains(&LinkEvent::Removed("veth3c4d".to_string())));
+        assert!(events.contains(&LinkEvent::Added(link(8, "eth1"))));
+        assert!(events.contains(&LinkEvent::Added(link(9, "veth5e6f"))));
+        assert_eq!(links.len(), 3);
+    }
+}
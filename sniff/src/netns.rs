@@ -0,0 +1,127 @@
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Error, Result};
+
+/// A network namespace of the rules and the `--netns` flag
+///
+/// * `web`: the namespace of the name created by `ip netns add`, in `/var/run/netns`
+/// * `1234`: the namespace of the process of the pid
+/// * `/proc/1234/ns/net`: the namespace of the path
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Netns {
+    name: String,
+    path: PathBuf,
+}
+
+impl FromStr for Netns {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let path = if s.starts_with('/') {
+            PathBuf::from(s)
+        } else if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+            PathBuf::from(format!("/proc/{}/ns/net", s))
+        } else if !s.is_empty() && !s.contains('/') && s != "." && s != ".." {
+            PathBuf::from(format!("/var/run/netns/{}", s))
+        } else {
+            return Err(anyhow!("'{}' is not a valid network namespace", s));
+        };
+
+        Ok(Self {
+            name: s.to_owned(),
+            path,
+        })
+    }
+}
+
+impl Display for Netns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Netns {
+    /// Returns the name of the namespace as configured, used as the `netns` label
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the namespace can be entered
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Run the closure with the calling thread in the namespace.
+    ///
+    /// The closure must not yield to the runtime, the thread is moved back to
+    /// its own namespace before returning.
+    pub fn enter<T, F: FnOnce() -> T>(&self, f: F) -> io::Result<T> {
+        let current = File::open("/proc/thread-self/ns/net")?;
+        let target = File::open(&self.path)?;
+        setns(&target)?;
+
+        // restored on drop, so a panicking closure does not leave the thread in the namespace
+        let _restore = Restore(current);
+        Ok(f())
+    }
+}
+
+/// Moves the thread back to the namespace of the file when dropped
+struct Restore(File);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        // a thread left in the namespace would attach the following programs there
+        setns(&self.0).expect("failed to restore the network namespace of the thread");
+    }
+}
+
+/// Run the closure in the namespace, or in the current namespace without one
+pub fn enter<T, F: FnOnce() -> T>(netns: Option<&Netns>, f: F) -> io::Result<T> {
+    match netns {
+        Some(netns) => netns.enter(f),
+        None => Ok(f()),
+    }
+}
+
+/// Returns the `netns` label value of the namespace, empty for the namespace of netsniff
+pub fn label(netns: Option<&Netns>) -> &str {
+    netns.map(Netns::name).unwrap_or_default()
+}
+
+fn setns(file: &File) -> io::Result<()> {
+    match unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::Netns;
+
+    #[test]
+    fn test_parse_netns() {
+        let netns: Netns = "web".parse().unwrap();
+        assert_eq!(netns.path, PathBuf::from("/var/run/netns/web"));
+        assert_eq!(netns.name(), "web");
+
+        let netns: Netns = "1234".parse().unwrap();
+        assert_eq!(netns.path, PathBuf::from("/proc/1234/ns/net"));
+
+        let netns: Netns = "/run/docker/netns/1f2e".parse().unwrap();
+        assert_eq!(netns.path, PathBuf::from("/run/docker/netns/1f2e"));
+
+        assert!("".parse::<Netns>().is_err());
+        assert!("../web".parse::<Netns>().is_err());
+    }
+}
@@ -1,15 +1,21 @@
 use std::{fmt::Display, net::Ipv4Addr};
 
 use chrono::Local;
-use network_types::ip::IpProto;
+use network_types::{ip::IpProto, udp::UdpHdr};
 use serde::Deserialize;
 use sniff_common::{Flow, ProtoHdr, RawPacket};
 
+use crate::tcp::TcpFlags;
+
 #[derive(Debug)]
 pub struct NetworkPacket {
     pub iface: String,
+    /// the `netns` label of the interface, empty in the network namespace of netsniff
+    pub netns: String,
     pub flow: Flow,
     pub pkt: Packet,
+    /// the packet stands for `sample_rate` packets of the interface, 1 when not sampled
+    pub sample_rate: u32,
 }
 
 #[derive(Debug)]
@@ -21,6 +27,15 @@ pub struct Packet {
     pub dst_ip: Ipv4Addr,
     pub dst: u16,
     pub length: u16,
+    /// only set for TCP packets
+    pub tcp_flags: Option<TcpFlags>,
+    /// sequence and acknowledgment numbers, only set for TCP packets
+    pub tcp_seq: Option<(u32, u32)>,
+    /// length of the whole L4 payload, which may exceed the captured `payload`
+    pub payload_len: u16,
+
+    /// leading bytes of the L4 payload, at most [RawPacket::PAYLOAD_LEN]
+    pub payload: Vec<u8>,
 }
 
 impl From<[u8; RawPacket::LEN]> for Packet {
@@ -30,28 +45,39 @@ impl From<[u8; RawPacket::LEN]> for Packet {
         let length = u16::from_be(ip_hdr.tot_len);
         let src_ip = Ipv4Addr::from(u32::from_be(ip_hdr.src_addr));
         let dst_ip = Ipv4Addr::from(u32::from_be(ip_hdr.dst_addr));
+        let payload_len = unsafe { (*raw_pkt).payload_len as usize }.min(RawPacket::PAYLOAD_LEN);
+        let payload = unsafe { (&(*raw_pkt).payload)[..payload_len].to_vec() };
         match unsafe { &(*raw_pkt).proto_hdr } {
             ProtoHdr::Tcp(tcp_hdr) => {
                 let source = u16::from_be(tcp_hdr.source);
                 let dst = u16::from_be(tcp_hdr.dest);
+                let hdr_len = (ip_hdr.ihl() as u16 + tcp_hdr.doff()) * 4;
                 Self {
                     src_ip,
                     dst_ip,
                     source,
                     dst,
                     length,
+                    tcp_flags: Some(TcpFlags::from(tcp_hdr)),
+                    tcp_seq: Some((u32::from_be(tcp_hdr.seq), u32::from_be(tcp_hdr.ack_seq))),
+                    payload_len: length.saturating_sub(hdr_len),
+                    payload,
                     proto: IpProto::Tcp,
                 }
             }
             ProtoHdr::Udp(udp_hdr) => {
                 let source = u16::from_be(udp_hdr.source);
-                let dst = udp_hdr.dest;
+                let dst = u16::from_be(udp_hdr.dest);
                 Self {
                     src_ip,
                     dst_ip,
                     source,
                     dst,
                     length,
+                    tcp_flags: None,
+                    tcp_seq: None,
+                    payload_len: u16::from_be(udp_hdr.len).saturating_sub(UdpHdr::LEN as u16),
+                    payload,
                     proto: IpProto::Udp,
                 }
             }
@@ -59,23 +85,38 @@ impl From<[u8; RawPacket::LEN]> for Packet {
     }
 }
 
+impl NetworkPacket {
+    /// Returns the traffic direction as used in metric labels
+    pub fn traffic(&self) -> &'static str {
+        match self.flow {
+            Flow::Ingress => "ingress",
+            Flow::Egress => "egress",
+            Flow::All => "all",
+        }
+    }
+}
+
 impl Display for NetworkPacket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let now = Local::now();
         write!(
             f,
-            "* {:<22}{:<10}{:<23} ->    {:<24}{:<7}length={:<5}",
+            "* {:<22}{:<10}{:<23} ->    {:<24}{:<7}length={:<5} {}",
             now.format("[%Y-%m-%d %H:%M:%S]").to_string(),
             format!("{:?}", self.flow),
             format!("{}:{}", self.pkt.src_ip, self.pkt.source),
             format!("{}:{}", self.pkt.dst_ip, self.pkt.dst),
             format!("{:?}", self.pkt.proto),
             self.pkt.length,
+            self.pkt
+                .tcp_flags
+                .map(|f| format!("flags={}", f))
+                .unwrap_or_default(),
         )
     }
 }
 
-#[derive(Debug, Deserialize, Default, Clone, Copy)]
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Proto {
     #[serde(alias = "all")]
     #[default]
@@ -95,3 +136,96 @@ impl From<i32> for Proto {
         }
     }
 }
+
+/// Fixtures shared by the tests of the other modules
+#[cfg(test)]
+pub(crate) mod test {
+    use std::net::Ipv4Addr;
+
+    use network_types::ip::IpProto;
+    use sniff_common::Flow;
+
+    use super::{NetworkPacket, Packet};
+    use crate::tcp::TcpFlags;
+
+    /// Builds a packet from the source to the destination address,
+    /// a 60 bytes egress UDP packet on `lo` unless set otherwise
+    pub struct PacketBuilder {
+        net_pkt: NetworkPacket,
+    }
+
+    pub fn packet(src: ([u8; 4], u16), dst: ([u8; 4], u16)) -> PacketBuilder {
+        PacketBuilder {
+            net_pkt: NetworkPacket {
+                iface: "lo".to_string(),
+                netns: String::new(),
+                flow: Flow::Egress,
+                pkt: Packet {
+                    proto: IpProto::Udp,
+                    src_ip: Ipv4Addr::from(src.0),
+                    source: src.1,
+                    dst_ip: Ipv4Addr::from(dst.0),
+                    dst: dst.1,
+                    length: 60,
+                    tcp_flags: None,
+                    tcp_seq: None,
+                    payload_len: 0,
+                    payload: Vec::new(),
+                },
+                sample_rate: 1,
+            },
+        }
+    }
+
+    /// Parse the TCP flags of the tcpdump notation, e.g. `S.` for SYN-ACK
+    pub fn tcp_flags(s: &str) -> TcpFlags {
+        TcpFlags {
+            syn: s.contains('S'),
+            ack: s.contains('.'),
+            fin: s.contains('F'),
+            rst: s.contains('R'),
+            psh: s.contains('P'),
+        }
+    }
+
+    impl PacketBuilder {
+        pub fn with_iface(mut self, iface: &str) -> Self {
+            self.net_pkt.iface = iface.to_string();
+            self
+        }
+
+        pub fn with_flow(mut self, flow: Flow) -> Self {
+            self.net_pkt.flow = flow;
+            self
+        }
+
+        pub fn with_proto(mut self, proto: IpProto) -> Self {
+            self.net_pkt.pkt.proto = proto;
+            self
+        }
+
+        pub fn with_length(mut self, length: u16) -> Self {
+            self.net_pkt.pkt.length = length;
+            self
+        }
+
+        /// Make it a TCP segment of the flags, see [tcp_flags]
+        pub fn with_tcp(mut self, flags: &str, seq: (u32, u32)) -> Self {
+            self.net_pkt.pkt.proto = IpProto::Tcp;
+            self.net_pkt.pkt.tcp_flags = Some(tcp_flags(flags));
+            self.net_pkt.pkt.tcp_seq = Some(seq);
+            self
+        }
+
+        /// Set the length of the L4 payload, the packet length includes 40 bytes of headers
+        pub fn with_payload_len(mut self, payload_len: u16) -> Self {
+            self.net_pkt.pkt.payload_len = payload_len;
+            self.net_pkt.pkt.length = 40 + payload_len;
+            self
+        }
+
+        pub fn build(self) -> NetworkPacket {
+            self.net_pkt
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    net::Ipv4Addr,
+};
+
+use chrono::{Local, SecondsFormat};
+use clap::ValueEnum;
+use colored::Colorize;
+use network_types::ip::IpProto;
+use serde::Serialize;
+use sniff_common::Flow;
+
+use crate::network::NetworkPacket;
+
+/// The format of the packets written to stdout by the capture subcommands
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// One line per packet, colored by traffic direction on a terminal
+    #[default]
+    Text,
+
+    /// One pretty-printed JSON object per packet
+    Json,
+
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// The fields of a packet in the `json` and `ndjson` formats, their names are stable
+#[derive(Debug, Serialize)]
+pub struct PacketRecord<'a> {
+    /// RFC 3339 time the packet was received by netsniff
+    pub timestamp: String,
+    pub iface: &'a str,
+    /// empty in the network namespace of netsniff
+    pub netns: &'a str,
+    /// `ingress` or `egress`
+    pub traffic: &'static str,
+    /// `tcp` or `udp`
+    pub protocol: &'static str,
+    pub src_ip: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_ip: Ipv4Addr,
+    pub dst_port: u16,
+    /// total length of the IP packet
+    pub length: u16,
+    pub payload_len: u16,
+    /// the set TCP flags (`syn`, `ack`, `fin`, `rst`, `psh`), `null` for UDP
+    pub tcp_flags: Option<Vec<&'static str>>,
+    pub tcp_seq: Option<u32>,
+    pub tcp_ack: Option<u32>,
+    /// the packet stands for `sample_rate` packets of the interface
+    pub sample_rate: u32,
+}
+
+impl<'a> From<&'a NetworkPacket> for PacketRecord<'a> {
+    fn from(net_pkt: &'a NetworkPacket) -> Self {
+        let pkt = &net_pkt.pkt;
+        let tcp_flags = pkt.tcp_flags.map(|flags| {
+            [
+                (flags.syn, "syn"),
+                (flags.ack, "ack"),
+                (flags.fin, "fin"),
+                (flags.rst, "rst"),
+                (flags.psh, "psh"),
+            ]
+            .into_iter()
+            .filter_map(|(set, name)| set.then_some(name))
+            .collect()
+        });
+
+        Self {
+            timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Micros, false),
+            iface: &net_pkt.iface,
+            netns: &net_pkt.netns,
+            traffic: net_pkt.traffic(),
+            protocol: match pkt.proto {
+                IpProto::Tcp => "tcp",
+                _ => "udp",
+            },
+            src_ip: pkt.src_ip,
+            src_port: pkt.source,
+            dst_ip: pkt.dst_ip,
+            dst_port: pkt.dst,
+            length: pkt.length,
+            payload_len: pkt.payload_len,
+            tcp_flags,
+            tcp_seq: pkt.tcp_seq.map(|(seq, _)| seq),
+            tcp_ack: pkt.tcp_seq.map(|(_, ack)| ack),
+            sample_rate: net_pkt.sample_rate,
+        }
+    }
+}
+
+/// Write the packet to stdout in the format, regardless of the log level
+pub fn write_packet(format: Format, net_pkt: &NetworkPacket) {
+    let mut stdout = io::stdout().lock();
+    let written = match format {
+        Format::Text if stdout.is_terminal() => {
+            let line = net_pkt.to_string();
+            match net_pkt.flow {
+                Flow::Ingress => writeln!(stdout, "{}", line.bright_green()),
+                _ => writeln!(stdout, "{}", line.bright_yellow()),
+            }
+        }
+        Format::Text => writeln!(stdout, "{}", net_pkt),
+        Format::Json => serde_json::to_writer_pretty(&mut stdout, &PacketRecord::from(net_pkt))
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(stdout)),
+        Format::Ndjson => serde_json::to_writer(&mut stdout, &PacketRecord::from(net_pkt))
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(stdout)),
+    };
+
+    // the reader of the pipe is gone, e.g. `netsniff tcp -i eth0 --output ndjson | head`
+    if written.is_err_and(|e| e.kind() == io::ErrorKind::BrokenPipe) {
+        std::process::exit(0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sniff_common::Flow;
+
+    use super::PacketRecord;
+    use crate::network;
+
+    #[test]
+    fn test_packet_record() {
+        let net_pkt = network::test::packet(([10, 0, 0, 1], 443), ([10, 0, 0, 2], 51234))
+            .with_iface("eth0")
+            .with_flow(Flow::Ingress)
+            .with_tcp("S.", (100, 200))
+            .build();
+
+        let mut value = serde_json::to_value(PacketRecord::from(&net_pkt)).unwrap();
+        assert!(value["timestamp"].is_string());
+        value.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "iface": "eth0",
+                "netns": "",
+                "traffic": "ingress",
+                "protocol": "tcp",
+                "src_ip": "10.0.0.1",
+                "src_port": 443,
+                "dst_ip": "10.0.0.2",
+                "dst_port": 51234,
+                "length": 60,
+                "payload_len": 0,
+                "tcp_flags": ["syn", "ack"],
+                "tcp_seq": 100,
+                "tcp_ack": 200,
+                "sample_rate": 1
+            })
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+use serde::Deserialize;
+
+/// Well-known service names that can be used in place of a port number
+const SERVICES: [(&str, u16); 16] = [
+    ("ftp", 21),
+    ("ssh", 22),
+    ("telnet", 23),
+    ("smtp", 25),
+    ("dns", 53),
+    ("http", 80),
+    ("ntp", 123),
+    ("ldap", 389),
+    ("https", 443),
+    ("mysql", 3306),
+    ("postgres", 5432),
+    ("redis", 6379),
+    ("http-alt", 8080),
+    ("kafka", 9092),
+    ("etcd", 2379),
+    ("mongodb", 27017),
+];
+
+/// An inclusive range of ports, a single port is a range of one.
+///
+/// It can be written in the configuration as a port number (`80`),
+/// a range (`"8000-8100"`) or a service name (`http`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortValue")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortValue {
+    Number(u16),
+    Text(String),
+}
+
+impl TryFrom<PortValue> for PortRange {
+    type Error = Error;
+
+    fn try_from(value: PortValue) -> Result<Self> {
+        match value {
+            PortValue::Number(port) => Ok(Self::single(port)),
+            PortValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl PortRange {
+    pub fn single(port: u16) -> Self {
+        Self {
+            start: port,
+            end: port,
+        }
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    /// Returns the port if the range only holds one
+    pub fn as_single(&self) -> Option<u16> {
+        (self.start == self.end).then_some(self.start)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        // service names may contain a dash as well, e.g. 'http-alt'
+        if let Ok(port) = parse_port(s) {
+            return Ok(Self::single(port));
+        }
+
+        if let Some((start, end)) = s.split_once('-') {
+            let (start, end) = (parse_port(start)?, parse_port(end)?);
+            if start > end {
+                return Err(anyhow!("invalid port range '{}', start is after end", s));
+            }
+            return Ok(Self { start, end });
+        }
+
+        parse_port(s).map(Self::single)
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.as_single() {
+            Some(port) => write!(f, "{}", port),
+            None => write!(f, "{}-{}", self.start, self.end),
+        }
+    }
+}
+
+fn parse_port(s: &str) -> Result<u16> {
+    let s = s.trim();
+    if let Ok(port) = s.parse::<u16>() {
+        return Ok(port);
+    }
+
+    SERVICES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        .map(|(_, port)| *port)
+        .ok_or_else(|| anyhow!("'{}' is neither a valid port nor a known service name", s))
+}
+
+/// A set of port ranges, an empty set matches no port.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<PortRange>,
+}
+
+impl FromIterator<PortRange> for PortSet {
+    fn from_iter<T: IntoIterator<Item = PortRange>>(iter: T) -> Self {
+        Self {
+            ranges: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl PortSet {
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|r| r.contains(port))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the ports that are listed on their own rather than as part of a range
+    pub fn singles(&self) -> impl Iterator<Item = u16> + '_ {
+        self.ranges.iter().filter_map(|r| r.as_single())
+    }
+
+    pub fn ranges(&self) -> &[PortRange] {
+        &self.ranges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PortRange;
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!("80".parse::<PortRange>().unwrap(), PortRange::single(80));
+        assert_eq!("dns".parse::<PortRange>().unwrap(), PortRange::single(53));
+        assert_eq!(
+            "8000-8100".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 8000,
+                end: 8100
+            }
+        );
+        assert_eq!(
+            "http-https".parse::<PortRange>().unwrap().to_string(),
+            "80-443"
+        );
+        assert!("8100-8000".parse::<PortRange>().is_err());
+        assert!("unknown".parse::<PortRange>().is_err());
+
+        let ports: Vec<PortRange> = serde_yaml::from_str(r#"[22, "8000-8100", http]"#).unwrap();
+        assert_eq!(ports.len(), 3);
+        assert!(ports[1].contains(8080));
+    }
+}
@@ -0,0 +1,280 @@
+use std::net::Ipv4Addr;
+
+use ipnetwork::Ipv4Network;
+use sniff_common::{Flow, Prefilter as Settings};
+
+use crate::{
+    expr::{Dir, Expr},
+    filter::Filter,
+    network::Proto,
+    port::PortRange,
+};
+
+/// The predicates of the rules compiled for the in-kernel prefilter of one traffic direction.
+///
+/// The predicate is the union of the predicates of every rule, so the eBPF classifier only
+/// drops packets that no rule could match, the userspace [Filter] still evaluates the others.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Predicate {
+    /// `None` when no rule matches the direction
+    pub proto: Option<Proto>,
+    /// peer cidrs, `None` when some rule matches any peer
+    pub cidrs: Option<Vec<Ipv4Network>>,
+    /// destination ports, `None` when some rule matches any port
+    pub ports: Option<Vec<PortRange>>,
+
+    /// one in `sample_rate` candidate packets is submitted, set per network interface
+    pub sample_rate: u32,
+    pub sample_flow_hash: bool,
+}
+
+/// A predicate matching every packet
+impl Default for Predicate {
+    fn default() -> Self {
+        Self {
+            proto: Some(Proto::ALL),
+            cidrs: None,
+            ports: None,
+            sample_rate: 1,
+            sample_flow_hash: false,
+        }
+    }
+}
+
+impl Predicate {
+    /// A predicate matching no packet, the identity of [Predicate::union]
+    fn nothing() -> Self {
+        Self {
+            proto: None,
+            cidrs: Some(Vec::new()),
+            ports: Some(Vec::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the predicate of the rule for the traffic direction, or `None` if
+    /// the rule never matches packets of that direction.
+    pub fn of_filter(filter: &Filter, flow: &Flow) -> Option<Self> {
+        let (ifaces, ports) = match flow {
+            Flow::Ingress => (&filter.in_iface_filter, &filter.in_port_filter),
+            Flow::Egress => (&filter.out_iface_filter, &filter.out_port_filter),
+            Flow::All => return Some(Self::default()),
+        };
+        if !filter.is_pass() && ifaces.is_empty() {
+            return None;
+        }
+
+        let ports = if !ports.is_empty() {
+            Some(ports.ranges().to_vec())
+        } else if !filter.dst_port_filter.is_empty() {
+            Some(filter.dst_port_filter.ranges().to_vec())
+        } else {
+            None
+        };
+        let mut predicate = Self {
+            proto: Some(filter.protocol),
+            cidrs: (!filter.cidrs.is_empty()).then(|| filter.cidrs.clone()),
+            ports,
+            ..Default::default()
+        };
+        if let Some(expr) = &filter.matcher {
+            predicate.narrow(expr, flow);
+        }
+
+        Some(predicate)
+    }
+
+    /// Narrow the predicate with the primitives the expression requires,
+    /// the primitives under `or` and `not` are left to userspace.
+    fn narrow(&mut self, expr: &Expr, flow: &Flow) {
+        let peer = match flow {
+            Flow::Ingress => Dir::Src,
+            _ => Dir::Dst,
+        };
+
+        for primitive in expr.conjuncts() {
+            match primitive {
+                Expr::Proto(proto) if matches!(self.proto, Some(Proto::ALL)) => {
+                    self.proto = Some(*proto)
+                }
+                Expr::Net(dir, net) if *dir == peer && self.cidrs.is_none() => {
+                    self.cidrs = Some(vec![*net])
+                }
+                Expr::Host(dir, addr) if *dir == peer && self.cidrs.is_none() => {
+                    self.cidrs = Ipv4Network::new(*addr, 32).ok().map(|net| vec![net])
+                }
+                Expr::Port(Dir::Dst, range) if self.ports.is_none() => {
+                    self.ports = Some(vec![*range])
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        let proto = match (self.proto, other.proto) {
+            (None, proto) | (proto, None) => proto,
+            (Some(a), Some(b)) if a as i32 == b as i32 => Some(a),
+            _ => Some(Proto::ALL),
+        };
+
+        let cidrs = match (self.cidrs, other.cidrs) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                // too many cidrs for the kernel map, leave them to userspace
+                (a.len() <= Settings::MAX_CIDRS as usize).then_some(a)
+            }
+            _ => None,
+        };
+
+        let ports = match (self.ports, other.ports) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                Some(a)
+            }
+            _ => None,
+        };
+
+        Self {
+            proto,
+            cidrs,
+            ports,
+            ..self
+        }
+    }
+
+    pub fn with_sampling(mut self, rate: u32, flow_hash: bool) -> Self {
+        self.sample_rate = rate.max(1);
+        self.sample_flow_hash = flow_hash;
+        self
+    }
+
+    /// The value of the `SNIFF_PROTOCOL` global
+    pub fn proto_num(&self) -> i32 {
+        match self.proto {
+            Some(Proto::TCP) => 1,
+            Some(Proto::UDP) => 2,
+            _ => 0,
+        }
+    }
+
+    /// The value of the `FILTER_FLAGS` global
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.cidrs.is_some() {
+            flags |= Settings::CIDR;
+        }
+        if self.ports.is_some() {
+            flags |= Settings::PORT;
+        }
+        flags
+    }
+
+    /// Returns the `FILTER_CIDRS` keys, as (prefix length, address in network byte order)
+    pub fn cidr_keys(&self) -> Vec<(u32, u32)> {
+        self.cidrs
+            .iter()
+            .flatten()
+            .map(|cidr| {
+                let network: Ipv4Addr = cidr.network();
+                (cidr.prefix() as u32, u32::from_ne_bytes(network.octets()))
+            })
+            .collect()
+    }
+
+    /// Returns the `FILTER_PORTS` bitmap, if the port check is enabled
+    pub fn port_bitmap(&self) -> Option<Vec<u64>> {
+        let ports = self.ports.as_ref()?;
+        let mut bitmap = vec![0u64; Settings::PORT_WORDS as usize];
+        for range in ports {
+            for port in range.start..=range.end {
+                bitmap[port as usize / 64] |= 1 << (port % 64);
+            }
+        }
+        Some(bitmap)
+    }
+}
+
+/// The in-kernel prefilter predicates of both traffic directions
+#[derive(Debug, Clone, Default)]
+pub struct Prefilter {
+    pub ingress: Predicate,
+    pub egress: Predicate,
+}
+
+impl Prefilter {
+    pub fn compile<'a, I>(filters: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Filter>,
+    {
+        let (mut ingress, mut egress) = (Predicate::nothing(), Predicate::nothing());
+        for filter in filters {
+            if let Some(predicate) = Predicate::of_filter(filter, &Flow::Ingress) {
+                ingress = ingress.union(predicate);
+            }
+            if let Some(predicate) = Predicate::of_filter(filter, &Flow::Egress) {
+                egress = egress.union(predicate);
+            }
+        }
+
+        Self { ingress, egress }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use ipnetwork::Ipv4Network;
+    use sniff_common::Prefilter as Settings;
+
+    use super::Prefilter;
+    use crate::filter::test::filter;
+
+    #[test]
+    fn test_compile_prefilter() {
+        let web = filter(
+            "{name: web, protocol: tcp, cidrs: [10.0.0.0/8], inIface: [lo], inPorts: [80, 443]}",
+        );
+        let dns = filter("{name: dns, protocol: udp, cidrs: [10.1.0.0/16], inIface: [lo], outIface: [lo], dstPorts: [53]}");
+        let prefilter = Prefilter::compile([&web, &dns]);
+
+        let ingress = &prefilter.ingress;
+        assert_eq!(ingress.proto_num(), 0);
+        assert_eq!(ingress.flags(), Settings::CIDR | Settings::PORT);
+        assert_eq!(ingress.cidrs.as_ref().unwrap().len(), 2);
+        let bitmap = ingress.port_bitmap().unwrap();
+        assert_ne!(bitmap[0] & (1 << 53), 0);
+        assert_ne!(bitmap[443 / 64] & (1 << (443 % 64)), 0);
+        assert_eq!(bitmap[0] & (1 << 22), 0);
+
+        // only the dns rule matches egress traffic
+        let egress = &prefilter.egress;
+        assert_eq!(egress.proto_num(), 2);
+        assert_eq!(
+            egress.cidrs,
+            Some(vec![Ipv4Network::from_str("10.1.0.0/16").unwrap()])
+        );
+    }
+
+    #[test]
+    fn test_compile_prefilter_expr() {
+        let any = filter("{name: any, inIface: [lo]}");
+        let https = filter(
+            "{name: https, inIface: [lo], match: 'tcp and dst port 443 and src net 1.1.1.0/24'}",
+        );
+
+        let ingress = Prefilter::compile([&https]).ingress;
+        assert_eq!(ingress.proto_num(), 1);
+        assert_eq!(
+            ingress.cidr_keys(),
+            vec![(24, u32::from_ne_bytes([1, 1, 1, 0]))]
+        );
+        assert_eq!(ingress.ports.unwrap()[0].to_string(), "443");
+
+        // a rule matching anything disables the checks
+        let ingress = Prefilter::compile([&https, &any]).ingress;
+        assert_eq!(ingress.flags(), 0);
+        assert_eq!(ingress.proto_num(), 0);
+    }
+}
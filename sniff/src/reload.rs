@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use ipnetwork::Ipv4Network;
+use log::{error, info, warn};
+use sniff_common::Flow;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+use crate::{
+    cidr::PrefixTree,
+    collector::{self, Identities},
+    config::{ConfigItem, Traffic},
+    filter::{Filter, MatchPolicy},
+    iface::{IfaceSet, SampleRates},
+    netns::Netns,
+    prefilter::Prefilter,
+};
+
+/// The rules of a configuration, compiled for matching packets
+pub struct RuleSet {
+    /// the network interface selectors of the rules by network namespace
+    pub ifaces: Vec<(Option<Netns>, IfaceSet)>,
+    pub flow: Flow,
+    pub trie: PrefixTree<Arc<Box<Filter>>>,
+    pub empty_filter: Vec<Arc<Box<Filter>>>,
+    pub prefilter: Prefilter,
+    pub policy: MatchPolicy,
+    pub sample_rates: SampleRates,
+    pub sample_flow_hash: bool,
+    /// the collector identities registered up front, with their label values
+    pub identities: Identities,
+    /// the configuration of each rule, used to tell the unchanged rules apart on reload
+    pub items: HashMap<String, ConfigItem>,
+}
+
+impl RuleSet {
+    pub fn build(config: &Traffic) -> Result<Self> {
+        let sample_rates = config.sample_rates();
+        let mut trie = PrefixTree::<Arc<Box<Filter>>>::new();
+        let mut ifaces: Vec<(Option<Netns>, IfaceSet)> = Vec::new();
+        let mut flow = 0x3;
+        let mut empty_filter: Vec<Arc<Box<Filter>>> = Vec::new();
+        let mut filters: Vec<Arc<Box<Filter>>> = Vec::new();
+        let mut identities = Vec::new();
+        let mut items = HashMap::new();
+
+        for (index, item) in config.rules.iter().flatten().enumerate() {
+            if let Some(source) = &item.source {
+                info!("build the '{}' rule from '{}'", item.name, source);
+            }
+            flow &= item.bind_flow() as i32;
+            items.insert(item.name.to_owned(), item.clone());
+
+            // only get the intersection of the network interfaces
+            let cidrs = item.cidrs.clone();
+            let filter_item = Filter::from(item.clone()).with_index(index);
+
+            for selector in [&filter_item.in_iface_filter, &filter_item.out_iface_filter] {
+                let selector = (filter_item.netns.clone(), selector.clone());
+                if !selector.1.is_empty() && !ifaces.contains(&selector) {
+                    ifaces.push(selector);
+                }
+            }
+
+            for identity in collector::filter_to_identity(&filter_item, &sample_rates) {
+                let label_values = if !filter_item.label_values.is_empty() {
+                    Some(filter_item.label_values.clone())
+                } else {
+                    None
+                };
+                identities.push((identity, label_values));
+            }
+
+            let filter: Arc<Box<Filter>> = Arc::new(Box::new(filter_item));
+            filters.push(filter.clone());
+            match cidrs {
+                Some(cidrs) if !cidrs.is_empty() => {
+                    /* handler non-empty filters */
+                    for cidr in cidrs {
+                        trie.insert(Ipv4Network::from_str(cidr.as_ref())?, filter.clone());
+                    }
+                }
+                _ => {
+                    /* handler empty filters */
+                    empty_filter.push(filter);
+                }
+            }
+        }
+
+        // only the candidate packets of the rules cross the ring buffer
+        let prefilter = Prefilter::compile(filters.iter().map(|f| f.as_ref().as_ref()));
+
+        Ok(Self {
+            ifaces,
+            flow: flow.into(),
+            trie,
+            empty_filter,
+            prefilter,
+            policy: config.match_policy,
+            sample_rates,
+            sample_flow_hash: config.sampling.flow_hash,
+            identities,
+            items,
+        })
+    }
+}
+
+/// Reloads the configuration file on SIGHUP and when it is modified.
+///
+/// The metrics settings (`constLabels`, `exportInterval` and `packetSizeBuckets`)
+/// are fixed at startup, only the rules are reloaded.
+pub struct Reloader {
+    path: String,
+    /// the configuration file and its included files and directories
+    files: Vec<PathBuf>,
+    const_labels: Vec<String>,
+    export_interval: String,
+    packet_size_buckets: Option<Vec<f64>>,
+}
+
+impl Reloader {
+    /// Interval between two checks of the modification time of the configuration file
+    const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    pub fn new(path: &str, config: &Traffic) -> Self {
+        Self {
+            path: path.to_owned(),
+            files: config.files.clone(),
+            const_labels: config.const_labels(),
+            export_interval: config.export_interval.to_owned(),
+            packet_size_buckets: config.packet_size_buckets(),
+        }
+    }
+
+    pub async fn watch(mut self, tx: mpsc::Sender<RuleSet>) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("failed to listen SIGHUP signal by err: {}", e);
+                return;
+            }
+        };
+        let mut tick = tokio::time::interval(Self::POLL_INTERVAL);
+        let mut modified = self.modified();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("received SIGHUP, reload configuration '{}'", self.path);
+                }
+                _ = tick.tick() => {
+                    let current = self.modified();
+                    if current.is_none() || current == modified {
+                        continue;
+                    }
+                    info!("configuration '{}' was modified, reload it", self.path);
+                }
+            }
+
+            let loaded = self.load();
+            // the included files may have changed with the reload
+            modified = self.modified();
+            match loaded {
+                Ok(rules) => {
+                    if tx.send(rules).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => error!(
+                    "failed to reload config '{}' by err {}, keep the current rules",
+                    self.path, e
+                ),
+            }
+        }
+    }
+
+    fn load(&mut self) -> Result<RuleSet> {
+        let config = Traffic::load_config_path(&self.path)?;
+        self.files = config.files.clone();
+        if config.const_labels() != self.const_labels {
+            return Err(anyhow!(
+                "constLabels can not be changed without restarting netsniff"
+            ));
+        }
+        if config.export_interval != self.export_interval
+            || config.packet_size_buckets() != self.packet_size_buckets
+        {
+            warn!("exportInterval and packetSizeBuckets changes only take effect after restarting netsniff");
+        }
+
+        RuleSet::build(&config)
+    }
+
+    /// Returns the latest modification time of the configuration files, adding
+    /// or removing a file of an included directory modifies the directory
+    fn modified(&self) -> Option<SystemTime> {
+        self.files
+            .iter()
+            .filter_map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, net::IpAddr};
+
+    use sniff_common::Flow;
+
+    use super::{Reloader, RuleSet};
+    use crate::config::Traffic;
+
+    #[test]
+    fn test_build_rule_set() {
+        let config_str = r#"
+constLabels: [team]
+rules:
+  - name: web
+    protocol: tcp
+    cidrs: ["10.0.0.0/8"]
+    inIface: [lo]
+    inPorts: [80]
+    constValues: {team: a}
+  - name: any
+    inIface: [lo]
+    outIface: [lo]
+"#;
+        let rules = RuleSet::build(&Traffic::parse(config_str, false).unwrap()).unwrap();
+        assert_eq!(rules.ifaces.len(), 1);
+        assert_eq!(rules.flow, Flow::All);
+        assert_eq!(rules.items.len(), 2);
+        // a tcp identity for the web rule, tcp and udp identities of both directions for the other
+        assert_eq!(rules.identities.len(), 5);
+        assert_eq!(rules.trie.search_all(IpAddr::from([10, 1, 1, 1])).len(), 1);
+        assert_eq!(rules.empty_filter[0].rule_name(), "any");
+        assert_eq!(rules.empty_filter[0].index, 1);
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let dir = std::env::temp_dir().join(format!("netsniff-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sniff.yaml");
+        fs::write(
+            &path,
+            "constLabels: [team]\ninclude: [a.yaml]\nrules:\n  - {name: main, inIface: [lo]}\n",
+        )
+        .unwrap();
+        fs::write(dir.join("a.yaml"), "rules:\n  - {name: a, inIface: [lo]}\n").unwrap();
+        fs::write(dir.join("b.yaml"), "rules:\n  - {name: b, inIface: [lo]}\n").unwrap();
+
+        let path = path.display().to_string();
+        let config = Traffic::load_config_path(&path).unwrap();
+        let mut reloader = Reloader::new(&path, &config);
+        assert!(reloader.load().unwrap().items.contains_key("a"));
+
+        // the rules are rebuilt from the newly included file
+        fs::write(
+            &path,
+            "constLabels: [team]\ninclude: [b.yaml]\nrules:\n  - {name: main, inIface: [lo]}\n",
+        )
+        .unwrap();
+        let rules = reloader.load().unwrap();
+        assert!(rules.items.contains_key("b") && !rules.items.contains_key("a"));
+        assert!(reloader.files.iter().any(|f| f.ends_with("b.yaml")));
+        assert!(!reloader.files.iter().any(|f| f.ends_with("a.yaml")));
+
+        // the labels of the registered metrics can not change
+        fs::write(
+            &path,
+            "constLabels: [owner]\nrules:\n  - {name: main, inIface: [lo]}\n",
+        )
+        .unwrap();
+        assert!(reloader.load().is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
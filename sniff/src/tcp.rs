@@ -0,0 +1,186 @@
+use std::fmt::Display;
+
+use network_types::tcp::TcpHdr;
+
+/// The control flags of a TCP segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpFlags {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+}
+
+impl From<&TcpHdr> for TcpFlags {
+    fn from(hdr: &TcpHdr) -> Self {
+        Self {
+            syn: hdr.syn() != 0,
+            ack: hdr.ack() != 0,
+            fin: hdr.fin() != 0,
+            rst: hdr.rst() != 0,
+            psh: hdr.psh() != 0,
+        }
+    }
+}
+
+/// Same notation as tcpdump, e.g. `[S.]` for a SYN-ACK segment
+impl Display for TcpFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut flags = String::with_capacity(5);
+        for (set, c) in [
+            (self.syn, 'S'),
+            (self.fin, 'F'),
+            (self.rst, 'R'),
+            (self.psh, 'P'),
+            (self.ack, '.'),
+        ] {
+            if set {
+                flags.push(c);
+            }
+        }
+        write!(f, "[{}]", flags)
+    }
+}
+
+/// The lifecycle state of a TCP connection, as seen by the observer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    Closing,
+    Closed,
+}
+
+impl TcpState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TcpState::SynSent => "syn_sent",
+            TcpState::SynReceived => "syn_received",
+            TcpState::Established => "established",
+            TcpState::Closing => "closing",
+            TcpState::Closed => "closed",
+        }
+    }
+
+    /// Whether the handshake of the connection has not completed yet
+    pub fn half_open(&self) -> bool {
+        matches!(self, TcpState::SynSent | TcpState::SynReceived)
+    }
+
+    /// Advance the state machine with the flags of a segment,
+    /// returning the next state and the lifecycle events the segment produced.
+    pub fn transition(
+        state: Option<TcpState>,
+        flags: TcpFlags,
+    ) -> (Option<TcpState>, Vec<TcpEvent>) {
+        let mut events = Vec::new();
+        let next = if flags.rst {
+            events.push(TcpEvent::Reset);
+            if state == Some(TcpState::SynSent) {
+                events.push(TcpEvent::Refused);
+            }
+            Some(TcpState::Closed)
+        } else if flags.syn && !flags.ack {
+            // retransmitted SYN segments are not new connection attempts
+            if state != Some(TcpState::SynSent) {
+                events.push(TcpEvent::Syn);
+            }
+            Some(TcpState::SynSent)
+        } else if flags.syn {
+            if state != Some(TcpState::SynReceived) {
+                events.push(TcpEvent::SynAck);
+            }
+            Some(TcpState::SynReceived)
+        } else if flags.fin {
+            // the segment does not tell which side sent it, see [crate::flow::FlowTable::track_tcp]
+            events.push(TcpEvent::Fin);
+            Some(TcpState::Closing)
+        } else if flags.ack && state == Some(TcpState::SynReceived) {
+            events.push(TcpEvent::Established);
+            Some(TcpState::Established)
+        } else {
+            state
+        };
+
+        (next, events)
+    }
+}
+
+/// The connection lifecycle events exported as metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpEvent {
+    /// a new connection attempt
+    Syn,
+    SynAck,
+    /// the three-way handshake completed
+    Established,
+    Fin,
+    Reset,
+    /// the connection attempt was answered by a reset
+    Refused,
+    /// the handshake did not complete in time
+    HalfOpen,
+}
+
+impl TcpEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TcpEvent::Syn => "syn",
+            TcpEvent::SynAck => "syn_ack",
+            TcpEvent::Established => "established",
+            TcpEvent::Fin => "fin",
+            TcpEvent::Reset => "rst",
+            TcpEvent::Refused => "refused",
+            TcpEvent::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TcpEvent, TcpState};
+    use crate::network::test::tcp_flags as flags;
+
+    #[test]
+    fn test_handshake_transition() {
+        let (state, events) = TcpState::transition(None, flags("S"));
+        assert_eq!(
+            (state, events),
+            (Some(TcpState::SynSent), vec![TcpEvent::Syn])
+        );
+
+        let (state, events) = TcpState::transition(state, flags("S."));
+        assert_eq!(
+            (state, events),
+            (Some(TcpState::SynReceived), vec![TcpEvent::SynAck])
+        );
+
+        let (state, events) = TcpState::transition(state, flags("."));
+        assert_eq!(
+            (state, events),
+            (Some(TcpState::Established), vec![TcpEvent::Established])
+        );
+
+        let (state, events) = TcpState::transition(state, flags("F."));
+        assert_eq!(
+            (state, events),
+            (Some(TcpState::Closing), vec![TcpEvent::Fin])
+        );
+    }
+
+    #[test]
+    fn test_refused_transition() {
+        let (state, _) = TcpState::transition(None, flags("S"));
+        let (state, events) = TcpState::transition(state, flags("R."));
+        assert_eq!(
+            (state, events),
+            (
+                Some(TcpState::Closed),
+                vec![TcpEvent::Reset, TcpEvent::Refused]
+            )
+        );
+        assert_eq!(flags("S.").to_string(), "[S.]");
+    }
+}
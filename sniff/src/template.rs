@@ -0,0 +1,272 @@
+use std::{env, ops::Range};
+
+use crate::{diagnostic::Location, util};
+
+/// An error of a `${...}` reference of the configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub message: String,
+    pub location: Location,
+}
+
+/// A rendered configuration source, remembering where its references were substituted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rendered {
+    pub text: String,
+    /// the substitutions of each line
+    substitutions: Vec<Vec<Substitution>>,
+}
+
+// A substituted reference, the byte columns of its value and of the reference in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Substitution {
+    rendered: Range<usize>,
+    source: Range<usize>,
+}
+
+impl From<&str> for Rendered {
+    /// A source without references
+    fn from(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            substitutions: Vec::new(),
+        }
+    }
+}
+
+impl Rendered {
+    /// Map a location of the rendered text back to the source,
+    /// a location within a substituted value points at its reference
+    pub fn source_location(&self, location: Location) -> Location {
+        let Some(substitutions) = self.substitutions.get(location.line - 1) else {
+            return location;
+        };
+        let column = location.column - 1;
+        let column = match substitutions
+            .iter()
+            .rev()
+            .find(|s| s.rendered.start <= column)
+        {
+            Some(s) if column < s.rendered.end => s.source.start,
+            Some(s) => s.source.end + column - s.rendered.end,
+            None => column,
+        };
+
+        Location {
+            line: location.line,
+            column: column + 1,
+        }
+    }
+}
+
+/// Substitute the `${VAR}` and `${VAR:-default}` references of the source.
+///
+/// * `${hostname}` is the host name of the current machine
+/// * the default is used if the variable is unset or empty
+/// * `$${` is a literal `${`, the comments are left as is
+/// * a value must not change the structure of the document,
+///   those with a newline, `#` or `: ` are rejected
+pub fn render<F>(source: &str, lookup: F) -> Result<Rendered, TemplateError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut rendered = Rendered {
+        text: String::with_capacity(source.len()),
+        substitutions: Vec::new(),
+    };
+    for (n, line) in source.split_inclusive('\n').enumerate() {
+        let mut substitutions = Vec::new();
+        let line_start = rendered.text.len();
+        let (code, comment) = line.split_at(comment_start(line).unwrap_or(line.len()));
+
+        let mut rest = code;
+        while let Some(start) = rest.find("${") {
+            let column = code.len() - rest.len() + start;
+            if rest[..start].ends_with('$') {
+                rendered.text.push_str(&rest[..start - 1]);
+                let value_start = rendered.text.len() - line_start;
+                rendered.text.push_str("${");
+                substitutions.push(Substitution {
+                    rendered: value_start..value_start + 2,
+                    source: column - 1..column + 2,
+                });
+                rest = &rest[start + 2..];
+                continue;
+            }
+            rendered.text.push_str(&rest[..start]);
+
+            let error = |message: String| TemplateError {
+                message,
+                location: Location {
+                    line: n + 1,
+                    column: column + 1,
+                },
+            };
+            let Some(end) = rest[start..].find('}') else {
+                return Err(error("unterminated '${' reference".to_string()));
+            };
+            let reference = &rest[start + 2..start + end];
+            let (name, default) = match reference.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (reference, None),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(error(format!("invalid variable name '{}'", name)));
+            }
+
+            let value = match (lookup(name).filter(|v| !v.is_empty()), default) {
+                (Some(value), _) => {
+                    if value.contains(['\n', '\r', '#'])
+                        || value.contains(": ")
+                        || value.ends_with(':')
+                    {
+                        return Err(error(format!(
+                            "the value of environment variable '{}' contains YAML syntax (a newline, '#' or ': ')",
+                            name
+                        )));
+                    }
+                    value
+                }
+                (None, Some(default)) => default.to_string(),
+                (None, None) => {
+                    return Err(error(format!(
+                        "environment variable '{}' is not set and has no default",
+                        name
+                    )))
+                }
+            };
+            let value_start = rendered.text.len() - line_start;
+            rendered.text.push_str(&value);
+            substitutions.push(Substitution {
+                rendered: value_start..value_start + value.len(),
+                source: column..column + end + 1,
+            });
+            rest = &rest[start + end + 1..];
+        }
+        rendered.text.push_str(rest);
+        rendered.text.push_str(comment);
+        rendered.substitutions.push(substitutions);
+    }
+
+    Ok(rendered)
+}
+
+// Returns the byte offset of the comment of the line, a `#` at its start or after
+// a whitespace outside of a quoted scalar
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote: Option<char> = None;
+    let mut prev = None;
+    // a backslash in a double quoted scalar, a quote closing a single quoted one
+    let (mut escaped, mut closed) = (false, false);
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => {
+                quote = None;
+                closed = q == '\'';
+                prev = Some(c);
+                continue;
+            }
+            Some(_) => {}
+            // `''` is a quote within a single quoted scalar
+            None if closed && c == '\'' => quote = Some(c),
+            // a quote only opens a scalar at its start, e.g. not in `don't`
+            None if (c == '"' || c == '\'')
+                && prev.is_none_or(|p: char| p.is_whitespace() || ":-[{,".contains(p)) =>
+            {
+                quote = Some(c)
+            }
+            None if c == '#' && prev.is_none_or(char::is_whitespace) => return Some(i),
+            None => {}
+        }
+        closed = false;
+        prev = Some(c);
+    }
+
+    None
+}
+
+/// Returns the value of the variable, from the built-in variables or the environment
+pub fn lookup(name: &str) -> Option<String> {
+    match name {
+        "hostname" => util::uname().ok().map(|info| info.node_name),
+        _ => env::var(name).ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::render;
+    use crate::diagnostic::Location;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "IFACE" => Some("eth0".to_string()),
+            "MAPPING" => Some("a: b".to_string()),
+            "COMMENT" => Some("eth0 # x".to_string()),
+            "EMPTY" => Some(String::new()),
+            "hostname" => Some("node-1".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let source = "inIface: [${IFACE}]\nnode: ${hostname}\n# ${UNSET}\n";
+        assert_eq!(
+            render(source, lookup).unwrap().text,
+            "inIface: [eth0]\nnode: node-1\n# ${UNSET}\n"
+        );
+
+        let source = "a: ${CLUSTER:-dev} ${EMPTY:-none} ${IFACE:-lo} $${IFACE} $5";
+        assert_eq!(
+            render(source, lookup).unwrap().text,
+            "a: dev none eth0 ${IFACE} $5"
+        );
+
+        // the references of trailing comments are left as is, unlike those of quoted scalars
+        let source = "port: 80  # ${UNSET}\nname: \"a #${IFACE}\" # ${UNSET}\nb: it's#${IFACE}";
+        assert_eq!(
+            render(source, lookup).unwrap().text,
+            "port: 80  # ${UNSET}\nname: \"a #eth0\" # ${UNSET}\nb: it's#eth0"
+        );
+        let source = "name: 'it''s #${IFACE}' # ${UNSET}";
+        assert_eq!(
+            render(source, lookup).unwrap().text,
+            "name: 'it''s #eth0' # ${UNSET}"
+        );
+    }
+
+    #[test]
+    fn test_source_location() {
+        let source = "a: 1\nb: [${IFACE}, $${X}, ${CLUSTER:-production}, x]\n";
+        let rendered = render(source, lookup).unwrap();
+        assert_eq!(rendered.text, "a: 1\nb: [eth0, ${X}, production, x]\n");
+
+        let location = |line, column| Location { line, column };
+        assert_eq!(rendered.source_location(location(1, 4)), location(1, 4));
+        assert_eq!(rendered.source_location(location(2, 5)), location(2, 5));
+        // within a value, the location is the reference
+        assert_eq!(rendered.source_location(location(2, 7)), location(2, 5));
+        assert_eq!(rendered.source_location(location(2, 11)), location(2, 15));
+        assert_eq!(rendered.source_location(location(2, 18)), location(2, 22));
+        assert_eq!(rendered.source_location(location(2, 30)), location(2, 47));
+    }
+
+    #[test]
+    fn test_render_error() {
+        let err = render("a: 1\nb: ${UNSET}", lookup).unwrap_err();
+        assert!(err.message.contains("'UNSET'"));
+        assert_eq!(err.location, Location { line: 2, column: 4 });
+
+        // a value must not change the structure of the document
+        let err = render("a: ${MAPPING}", lookup).unwrap_err();
+        assert_eq!(err.location, Location { line: 1, column: 4 });
+        assert!(render("a: ${COMMENT}", lookup).is_err());
+        assert!(render("a: ${MAPPING:-a: b} # ${UNSET}", lookup).is_err());
+
+        assert!(render("a: ${IFACE", lookup).is_err());
+        assert!(render("a: ${IF ACE}", lookup).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashSet, fmt::Display};
+
+use network_types::ip::IpProto;
+
+use crate::network::Packet;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+
+/// The attributes extracted from a TLS ClientHello message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+    /// the extensions are cut off, by the captured payload length or because the message
+    /// spans several segments, so the server name or ALPN may be missing
+    pub truncated: bool,
+}
+
+/// Decode the TLS ClientHello carried by the packet,
+/// only TCP packets sent to one of the given ports are considered.
+pub fn decode(pkt: &Packet, ports: &HashSet<u16>) -> Option<ClientHello> {
+    if !matches!(pkt.proto, IpProto::Tcp) || !ports.contains(&pkt.dst) {
+        return None;
+    }
+
+    ClientHello::parse(&pkt.payload)
+}
+
+impl ClientHello {
+    /// Parse a ClientHello from the beginning of a TLS record.
+    ///
+    /// The payload may be truncated, extensions that could not be fully read are skipped.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader::new(buf);
+
+        // record header: content type, legacy version and length
+        if r.u8()? != CONTENT_TYPE_HANDSHAKE || r.u8()? != 0x03 {
+            return None;
+        }
+        r.skip(3)?;
+
+        // handshake header: type and 24 bits length
+        if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+            return None;
+        }
+        r.skip(3)?;
+
+        // client version and random
+        r.skip(2 + 32)?;
+        // session id, cipher suites and compression methods
+        let len = r.u8()? as usize;
+        r.skip(len)?;
+        let len = r.u16()? as usize;
+        r.skip(len)?;
+        let len = r.u8()? as usize;
+        r.skip(len)?;
+
+        let mut hello = ClientHello::default();
+        let ext_len = r.u16()? as usize;
+        hello.truncated = r.rest().len() < ext_len;
+        let mut exts = Reader::new(r.rest().get(..ext_len).unwrap_or(r.rest()));
+        while let (Some(ext_type), Some(len)) = (exts.u16(), exts.u16()) {
+            let Some(data) = exts.take(len as usize) else {
+                break;
+            };
+            match ext_type {
+                EXT_SERVER_NAME => hello.server_name = parse_server_name(data),
+                EXT_ALPN => hello.alpn = parse_alpn(data),
+                _ => {}
+            }
+        }
+
+        Some(hello)
+    }
+
+    pub fn alpn(&self) -> String {
+        self.alpn.join(",")
+    }
+}
+
+impl Display for ClientHello {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "  tls client hello sni={} alpn=[{}]",
+            self.server_name.as_deref().unwrap_or("-"),
+            self.alpn(),
+        )
+    }
+}
+
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut r = Reader::new(data);
+    let list_len = r.u16()? as usize;
+    let mut list = Reader::new(r.take(list_len)?);
+    while let Some(name_type) = list.u8() {
+        let len = list.u16()? as usize;
+        let name = list.take(len)?;
+        // only the 'host_name' type is defined
+        if name_type == 0 {
+            return Some(String::from_utf8_lossy(name).to_string());
+        }
+    }
+
+    None
+}
+
+fn parse_alpn(data: &[u8]) -> Vec<String> {
+    let mut protocols = Vec::new();
+    let mut r = Reader::new(data);
+    let Some(list_len) = r.u16() else {
+        return protocols;
+    };
+    let mut list = Reader::new(r.rest().get(..list_len as usize).unwrap_or(r.rest()));
+    while let Some(len) = list.u8() {
+        let Some(proto) = list.take(len as usize) else {
+            break;
+        };
+        protocols.push(String::from_utf8_lossy(proto).to_string());
+    }
+
+    protocols
+}
+
+/// A cursor over a big-endian byte buffer
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let data = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(data)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.buf.get(self.pos..).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ClientHello;
+
+    fn client_hello(host: &str, alpn: &[&str]) -> Vec<u8> {
+        let mut exts = Vec::new();
+
+        let mut sni = vec![0x00];
+        sni.extend((host.len() as u16).to_be_bytes());
+        sni.extend(host.as_bytes());
+        exts.extend(0x0000u16.to_be_bytes());
+        exts.extend((sni.len() as u16 + 2).to_be_bytes());
+        exts.extend((sni.len() as u16).to_be_bytes());
+        exts.extend(sni);
+
+        let mut protos = Vec::new();
+        for p in alpn {
+            protos.push(p.len() as u8);
+            protos.extend(p.as_bytes());
+        }
+        exts.extend(0x0010u16.to_be_bytes());
+        exts.extend((protos.len() as u16 + 2).to_be_bytes());
+        exts.extend((protos.len() as u16).to_be_bytes());
+        exts.extend(protos);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend([0u8; 32]);
+        body.push(0); // session id
+        body.extend([0x00, 0x02, 0x13, 0x01]); // cipher suites
+        body.extend([0x01, 0x00]); // compression methods
+        body.extend((exts.len() as u16).to_be_bytes());
+        body.extend(exts);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend((body.len() as u16).to_be_bytes());
+        handshake.extend(body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        let buf = client_hello("example.com", &["h2", "http/1.1"]);
+        let hello = ClientHello::parse(&buf).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn(), "h2,http/1.1");
+        assert!(!hello.truncated);
+
+        // the ALPN extension is cut off, but the server name is still available
+        let hello = ClientHello::parse(&buf[..buf.len() - 4]).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert!(hello.truncated);
+
+        assert!(ClientHello::parse(b"GET / HTTP/1.1\r\n").is_none());
+    }
+}